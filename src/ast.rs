@@ -1,39 +1,141 @@
-use std::fmt::Debug;
-use std::ffi::CString;
+use std::fmt::{self, Debug};
+use std::ffi::{CString, CStr};
 use std::os::raw::c_uint;
 use std::ptr::null_mut;
 
 use llvm::prelude::*;
+use llvm::LLVMTypeKind;
 use llvm::core::*;
-use llvm::LLVMRealPredicate;
+use llvm::{LLVMIntPredicate, LLVMRealPredicate};
 use llvm::analysis::{LLVMVerifyFunction, LLVMVerifierFailureAction};
 
-use crate::parser::Parser;
+use crate::parser::{Parser, NamedValue};
 
 pub trait AST: Debug {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef;
 }
 
+// Type of a value
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    Int,
+    Double,
+    Bool,
+    Str,
+    Array,
+    // A struct, by name
+    Struct(String),
+    // A function, by its parameter and return types
+    Function(Vec<Type>, Box<Type>),
+    // A tuple, by its element types, of which there are at least two
+    Tuple(Vec<Type>),
+    // A type still to be inferred, only seen during type checking
+    Var(usize),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Double => write!(f, "double"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "string"),
+            Type::Array => write!(f, "array"),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Function(arg_types, return_type) => {
+                let arg_types: Vec<String> = arg_types.iter().map(|ty| ty.to_string()).collect();
+                write!(f, "({}) -> {}", arg_types.join(", "), return_type)
+            }
+            Type::Tuple(types) => {
+                let types: Vec<String> = types.iter().map(|ty| ty.to_string()).collect();
+                write!(f, "({})", types.join(", "))
+            }
+            Type::Var(n) => write!(f, "?{}", n),
+        }
+    }
+}
+
 // Expression
 #[derive(Debug)]
 pub enum Expr {
+    IntegerExpr(IntegerExpr),
     NumberExpr(NumberExpr),
+    BooleanExpr(BooleanExpr),
+    StringExpr(StringExpr),
+    ArrayExpr(ArrayExpr),
+    TupleExpr(TupleExpr),
+    IndexExpr(IndexExpr),
+    FieldExpr(FieldExpr),
     VariableExpr(VariableExpr),
+    UnaryExpr(UnaryExpr),
     BinaryExpr(BinaryExpr),
     CallExpr(CallExpr),
+    LambdaExpr(LambdaExpr),
+    ClosureExpr(ClosureExpr),
+    DefExpr(DefExpr),
+    IfExpr(IfExpr),
+    ForExpr(ForExpr),
+    VarExpr(VarExpr),
+    LetExpr(LetExpr),
+    WhileExpr(WhileExpr),
+    BreakExpr(BreakExpr),
+    ContinueExpr(ContinueExpr),
+    BlockExpr(BlockExpr),
 }
 
 impl AST for Expr {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
         match self {
+            Expr::IntegerExpr(i) => i.codegen(parser),
             Expr::NumberExpr(n) => n.codegen(parser),
+            Expr::BooleanExpr(b) => b.codegen(parser),
+            Expr::StringExpr(s) => s.codegen(parser),
+            Expr::ArrayExpr(a) => a.codegen(parser),
+            Expr::TupleExpr(t) => t.codegen(parser),
+            Expr::IndexExpr(i) => i.codegen(parser),
+            Expr::FieldExpr(f) => f.codegen(parser),
             Expr::VariableExpr(v) => v.codegen(parser),
+            Expr::UnaryExpr(u) => u.codegen(parser),
             Expr::BinaryExpr(b) => b.codegen(parser),
             Expr::CallExpr(c) => c.codegen(parser),
+            Expr::LambdaExpr(_) => panic!("lambda left by closure conversion"),
+            Expr::ClosureExpr(c) => c.codegen(parser),
+            Expr::DefExpr(_) => panic!("nested definition left by closure conversion"),
+            Expr::IfExpr(i) => i.codegen(parser),
+            Expr::ForExpr(f) => f.codegen(parser),
+            Expr::VarExpr(v) => v.codegen(parser),
+            Expr::LetExpr(l) => l.codegen(parser),
+            Expr::WhileExpr(w) => w.codegen(parser),
+            Expr::BreakExpr(b) => b.codegen(parser),
+            Expr::ContinueExpr(c) => c.codegen(parser),
+            Expr::BlockExpr(b) => b.codegen(parser),
+        }
+    }
+}
+
+// Integer literal
+#[derive(Debug)]
+pub struct IntegerExpr {
+    pub val: i64,
+}
+
+impl IntegerExpr {
+    unsafe fn codegen_with_type(&self, ty: LLVMTypeRef) -> LLVMValueRef {
+        if LLVMGetTypeKind(ty) == LLVMTypeKind::LLVMIntegerTypeKind {
+            LLVMConstInt(ty, self.val as u64, 1)
+        } else {
+            LLVMConstReal(ty, self.val as f64)
         }
     }
 }
 
+impl AST for IntegerExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // Used on its own, an integer literal is a double.
+        self.codegen_with_type(parser.get_double_type())
+    }
+}
+
 // Number
 #[derive(Debug)]
 pub struct NumberExpr {
@@ -46,6 +148,196 @@ impl AST for NumberExpr {
     }
 }
 
+// Boolean literal
+#[derive(Debug)]
+pub struct BooleanExpr {
+    pub val: bool,
+}
+
+impl AST for BooleanExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        LLVMConstInt(parser.get_bool_type(), self.val as u64, 0)
+    }
+}
+
+// String literal
+#[derive(Debug)]
+pub struct StringExpr {
+    pub val: String,
+}
+
+impl AST for StringExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // The characters live in a global constant, the value points to them.
+        let val = CString::new(self.val.clone()).unwrap().into_raw();
+        LLVMBuildGlobalStringPtr(parser.builder(), val, CString::new("str").unwrap().into_raw())
+    }
+}
+
+// Array literal
+#[derive(Debug)]
+pub struct ArrayExpr {
+    pub elements: Vec<Box<Expr>>,
+}
+
+impl AST for ArrayExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // Allocate the array through the runtime, then fill it in.
+        let function = LLVMGetNamedFunction(parser.module(), CString::new("array").unwrap().into_raw());
+        let mut args = vec![LLVMConstInt(parser.get_int_type(), self.elements.len() as u64, 1)];
+        let array = LLVMBuildCall(parser.builder(), function, args.as_mut_ptr(), 1, CString::new("arraytmp").unwrap().into_raw());
+
+        let data = build_array_field(parser, array, 1, "data");
+        for (i, element) in self.elements.iter().enumerate() {
+            let value = codegen_with_type(element, parser.get_double_type(), parser);
+            let mut indices = vec![LLVMConstInt(parser.get_int_type(), i as u64, 1)];
+            let pointer = LLVMBuildGEP(parser.builder(), data, indices.as_mut_ptr(), 1, CString::new("elementptr").unwrap().into_raw());
+            LLVMBuildStore(parser.builder(), value, pointer);
+        }
+
+        array
+    }
+}
+
+// Tuple, a first-class LLVM struct passed and returned by value
+#[derive(Debug)]
+pub struct TupleExpr {
+    pub elements: Vec<Box<Expr>>,
+}
+
+impl TupleExpr {
+    // Integer literal elements take the types of the tuple type's elements.
+    unsafe fn codegen_with_type(&self, ty: LLVMTypeRef, parser: &mut Parser) -> LLVMValueRef {
+        let elements: Vec<LLVMValueRef> = self.elements.iter().enumerate()
+            .map(|(i, element)| codegen_with_type(element, LLVMStructGetTypeAtIndex(ty, i as c_uint), parser))
+            .collect();
+        build_tuple(parser, ty, &elements)
+    }
+}
+
+impl AST for TupleExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let elements: Vec<LLVMValueRef> = self.elements.iter().map(|element| element.codegen(parser)).collect();
+        let mut element_types: Vec<LLVMTypeRef> = elements.iter().map(|element| LLVMTypeOf(*element)).collect();
+        let ty = LLVMStructTypeInContext(parser.context(), element_types.as_mut_ptr(), element_types.len() as c_uint, 0);
+        build_tuple(parser, ty, &elements)
+    }
+}
+
+unsafe fn build_tuple(parser: &mut Parser, ty: LLVMTypeRef, elements: &[LLVMValueRef]) -> LLVMValueRef {
+    let mut tuple = LLVMGetUndef(ty);
+    for (i, element) in elements.iter().enumerate() {
+        tuple = LLVMBuildInsertValue(parser.builder(), tuple, *element, i as c_uint, CString::new("tupletmp").unwrap().into_raw());
+    }
+    tuple
+}
+
+// Array element
+#[derive(Debug)]
+pub struct IndexExpr {
+    pub array: Box<Expr>,
+    pub index: Box<Expr>,
+}
+
+impl AST for IndexExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let array = self.array.codegen(parser);
+        let index = codegen_with_type(&self.index, parser.get_int_type(), parser);
+        let (pointer, error_block, merge_block) = build_element_pointer(parser, array, index);
+        let value = LLVMBuildLoad(parser.builder(), pointer, CString::new("element").unwrap().into_raw());
+        LLVMBuildBr(parser.builder(), merge_block);
+        let load_block = LLVMGetInsertBlock(parser.builder());
+
+        // An element out of range reads as 0.0.
+        LLVMPositionBuilderAtEnd(parser.builder(), merge_block);
+        let phi = LLVMBuildPhi(parser.builder(), parser.get_double_type(), CString::new("elementtmp").unwrap().into_raw());
+        let mut values = vec![value, LLVMConstReal(parser.get_double_type(), 0.0)];
+        let mut blocks = vec![load_block, error_block];
+        LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+        phi
+    }
+}
+
+// Load a field of an array: 0 for its length, 1 for its elements.
+unsafe fn build_array_field(parser: &mut Parser, array: LLVMValueRef, field: u32, name: &str) -> LLVMValueRef {
+    let pointer = LLVMBuildStructGEP(parser.builder(), array, field, CString::new(format!("{}ptr", name)).unwrap().into_raw());
+    LLVMBuildLoad(parser.builder(), pointer, CString::new(name).unwrap().into_raw())
+}
+
+// Generate the pointer to an array element, checking the index against the
+// length of the array. An index out of range calls the runtime error hook
+// instead, in a block which jumps to the returned merge block. The builder
+// is left where the index is valid, which also has to jump to the merge
+// block once done with the element.
+unsafe fn build_element_pointer(parser: &mut Parser, array: LLVMValueRef, index: LLVMValueRef) -> (LLVMValueRef, LLVMBasicBlockRef, LLVMBasicBlockRef) {
+    let len = build_array_field(parser, array, 0, "len");
+
+    // Negative indices are too large as unsigned.
+    let in_range = LLVMBuildICmp(parser.builder(), LLVMIntPredicate::LLVMIntULT, index, len, CString::new("inrange").unwrap().into_raw());
+    let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+    let element_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("element").unwrap().into_raw());
+    let error_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("indexerror").unwrap().into_raw());
+    let merge_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("elementcont").unwrap().into_raw());
+    LLVMBuildCondBr(parser.builder(), in_range, element_block, error_block);
+
+    LLVMPositionBuilderAtEnd(parser.builder(), error_block);
+    let index_error = get_function(parser, "index_error", &[Type::Int, Type::Int], None);
+    let mut args = vec![index, len];
+    LLVMBuildCall(parser.builder(), index_error, args.as_mut_ptr(), 2, CString::new("").unwrap().into_raw());
+    LLVMBuildBr(parser.builder(), merge_block);
+
+    LLVMPositionBuilderAtEnd(parser.builder(), element_block);
+    let data = build_array_field(parser, array, 1, "data");
+    let mut indices = vec![index];
+    let pointer = LLVMBuildGEP(parser.builder(), data, indices.as_mut_ptr(), 1, CString::new("elementptr").unwrap().into_raw());
+    (pointer, error_block, merge_block)
+}
+
+// Get a function the generated code relies on, declaring it on first use.
+// Without a return type, the function returns void.
+unsafe fn get_function(parser: &mut Parser, name: &str, arg_types: &[Type], return_type: Option<Type>) -> LLVMValueRef {
+    let name = CString::new(name).unwrap().into_raw();
+    let function = LLVMGetNamedFunction(parser.module(), name);
+    if function != null_mut() {
+        return function;
+    }
+
+    let function_type = match return_type {
+        Some(return_type) => parser.get_function_type(arg_types, &return_type),
+        None => {
+            let mut arg_types: Vec<LLVMTypeRef> = arg_types.iter().map(|ty| parser.get_type(ty)).collect();
+            LLVMFunctionType(LLVMVoidTypeInContext(parser.context()), arg_types.as_mut_ptr(), arg_types.len() as c_uint, 0)
+        }
+    };
+    LLVMAddFunction(parser.module(), name, function_type)
+}
+
+// Struct field
+#[derive(Debug)]
+pub struct FieldExpr {
+    pub object: Box<Expr>,
+    pub field: String,
+}
+
+impl AST for FieldExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let object = self.object.codegen(parser);
+        let pointer = build_field_pointer(parser, object, &self.field);
+        LLVMBuildLoad(parser.builder(), pointer, CString::new(self.field.clone()).unwrap().into_raw())
+    }
+}
+
+// Generate the pointer to a field of a struct, from the pointer to the struct.
+unsafe fn build_field_pointer(parser: &mut Parser, object: LLVMValueRef, field: &str) -> LLVMValueRef {
+    let struct_type = LLVMGetElementType(LLVMTypeOf(object));
+    let name = CStr::from_ptr(LLVMGetStructName(struct_type)).to_str().unwrap().to_owned();
+    let index = match parser.get_struct_fields(&name).and_then(|fields| fields.iter().position(|f| f == field)) {
+        Some(index) => index,
+        None => panic!("unknown field <{}> of struct <{}>", field, name)
+    };
+    LLVMBuildStructGEP(parser.builder(), object, index as c_uint, CString::new(format!("{}ptr", field)).unwrap().into_raw())
+}
+
 // Variable
 #[derive(Debug)]
 pub struct VariableExpr {
@@ -55,8 +347,40 @@ pub struct VariableExpr {
 impl AST for VariableExpr {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
         match parser.get_named_value(self.name.clone()) {
-            Some(value) => *value,
-            None => panic!("unknown variable name <{}>", self.name)
+            Some(NamedValue::Variable(alloca)) => LLVMBuildLoad(parser.builder(), *alloca, CString::new(self.name.clone()).unwrap().into_raw()),
+            Some(NamedValue::Value(value)) => *value,
+            None => {
+                // A function name evaluates to a closure of the function.
+                let function = LLVMGetNamedFunction(parser.module(), CString::new(self.name.clone()).unwrap().into_raw());
+                if function == null_mut() {
+                    panic!("unknown variable name <{}>", self.name);
+                }
+                get_function_closure(parser, function, &self.name)
+            }
+        }
+    }
+}
+
+// Unary operation
+#[derive(Debug)]
+pub struct UnaryExpr {
+    pub op: char,
+    pub operand: Box<Expr>,
+}
+
+impl AST for UnaryExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        if self.op != '-' && self.op != '!' {
+            // Fall back to a user-defined operator, e.g. 'unary~'.
+            return build_operator_call(parser, &format!("unary{}", self.op), &[&self.operand], "unop");
+        }
+
+        let operand = self.operand.codegen(parser);
+        let is_int = LLVMTypeOf(operand) == parser.get_int_type();
+        match self.op {
+            '-' if is_int => LLVMBuildNeg(parser.builder(), operand, CString::new("negtmp").unwrap().into_raw()),
+            '-' => LLVMBuildFNeg(parser.builder(), operand, CString::new("negtmp").unwrap().into_raw()),
+            _ => LLVMBuildNot(parser.builder(), operand, CString::new("nottmp").unwrap().into_raw()),
         }
     }
 }
@@ -64,74 +388,965 @@ impl AST for VariableExpr {
 // Binary operation
 #[derive(Debug)]
 pub struct BinaryExpr {
-    pub op: char,
+    pub op: String,
     pub lhs: Box<Expr>,
     pub rhs: Box<Expr>,
 }
 
 impl AST for BinaryExpr {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
-        let lhs = self.lhs.codegen(parser);
-        let rhs = self.rhs.codegen(parser);
-        match self.op {
-            '+' => LLVMBuildFAdd(parser.builder(), lhs, rhs, CString::new("addtmp").unwrap().into_raw()),
-            '-' => LLVMBuildFSub(parser.builder(), lhs, rhs, CString::new("subtmp").unwrap().into_raw()),
-            '*' => LLVMBuildFMul(parser.builder(), lhs, rhs, CString::new("multmp").unwrap().into_raw()),
-            '<' => {
-                let cmp_value = LLVMBuildFCmp(parser.builder(), LLVMRealPredicate::LLVMRealULT, lhs, rhs, CString::new("cmptmp").unwrap().into_raw());
-                LLVMBuildUIToFP(parser.builder(), cmp_value, parser.get_double_type(), CString::new("booltmp").unwrap().into_raw())
+        // Assignment doesn't evaluate its LHS, it stores into the variable
+        // or array element.
+        if self.op == "=" {
+            let name = match *self.lhs {
+                Expr::VariableExpr(ref v) => v.name.clone(),
+                Expr::IndexExpr(ref i) => {
+                    // An element out of range is not stored to.
+                    let array = i.array.codegen(parser);
+                    let index = codegen_with_type(&i.index, parser.get_int_type(), parser);
+                    let value = codegen_with_type(&self.rhs, parser.get_double_type(), parser);
+                    let (pointer, _, merge_block) = build_element_pointer(parser, array, index);
+                    LLVMBuildStore(parser.builder(), value, pointer);
+                    LLVMBuildBr(parser.builder(), merge_block);
+                    LLVMPositionBuilderAtEnd(parser.builder(), merge_block);
+                    return value;
+                }
+                Expr::FieldExpr(ref f) => {
+                    let object = f.object.codegen(parser);
+                    let value = codegen_with_type(&self.rhs, parser.get_double_type(), parser);
+                    let pointer = build_field_pointer(parser, object, &f.field);
+                    LLVMBuildStore(parser.builder(), value, pointer);
+                    return value;
+                }
+                _ => panic!("invalid assignment target {:?}", self.lhs)
+            };
+
+            let alloca = match parser.get_named_value(name.clone()) {
+                Some(NamedValue::Variable(alloca)) => *alloca,
+                Some(NamedValue::Value(_)) => panic!("cannot assign to immutable <{}>", name),
+                None => panic!("unknown variable name <{}>", name)
+            };
+            let ty = LLVMGetElementType(LLVMTypeOf(alloca));
+            let value = codegen_with_type(&self.rhs, ty, parser);
+            if LLVMTypeOf(value) != ty {
+                panic!("cannot assign {} to <{}> of type {}", type_name(LLVMTypeOf(value)), name, type_name(ty));
+            }
+            LLVMBuildStore(parser.builder(), value, alloca);
+
+            // Return the value, so that assignments can be chained.
+            return value;
+        }
+
+        // Logical operators only evaluate their RHS when needed.
+        if self.op == "&&" || self.op == "||" {
+            let lhs_value = self.lhs.codegen(parser);
+
+            let lhs_block = LLVMGetInsertBlock(parser.builder());
+            let function = LLVMGetBasicBlockParent(lhs_block);
+            let rhs_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("rhs").unwrap().into_raw());
+            let merge_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("logiccont").unwrap().into_raw());
+            if self.op == "&&" {
+                LLVMBuildCondBr(parser.builder(), lhs_value, rhs_block, merge_block);
+            } else {
+                LLVMBuildCondBr(parser.builder(), lhs_value, merge_block, rhs_block);
+            }
+
+            LLVMPositionBuilderAtEnd(parser.builder(), rhs_block);
+            let rhs_value = self.rhs.codegen(parser);
+            LLVMBuildBr(parser.builder(), merge_block);
+            let rhs_block = LLVMGetInsertBlock(parser.builder());
+
+            // Skipping the RHS means false for '&&' and true for '||'.
+            LLVMPositionBuilderAtEnd(parser.builder(), merge_block);
+            let short_value = LLVMConstInt(parser.get_bool_type(), (self.op == "||") as u64, 0);
+            let phi = LLVMBuildPhi(parser.builder(), parser.get_bool_type(), CString::new("logictmp").unwrap().into_raw());
+            let mut values = vec![short_value, rhs_value];
+            let mut blocks = vec![lhs_block, rhs_block];
+            LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+            return phi;
+        }
+
+        if !is_builtin_binary(&self.op) {
+            // Fall back to a user-defined operator, e.g. 'binary|'.
+            return build_operator_call(parser, &format!("binary{}", self.op), &[&self.lhs, &self.rhs], "binop");
+        }
+
+        // An integer literal takes the type of the other operand.
+        let (lhs, rhs) = if is_untyped(&self.lhs) {
+            let rhs = self.rhs.codegen(parser);
+            (codegen_with_type(&self.lhs, LLVMTypeOf(rhs), parser), rhs)
+        } else {
+            let lhs = self.lhs.codegen(parser);
+            (lhs, codegen_with_type(&self.rhs, LLVMTypeOf(lhs), parser))
+        };
+
+        if LLVMTypeOf(lhs) == parser.get_int_type() && LLVMTypeOf(rhs) == parser.get_int_type() {
+            return match self.op.as_str() {
+                "+" => LLVMBuildAdd(parser.builder(), lhs, rhs, CString::new("addtmp").unwrap().into_raw()),
+                "-" => LLVMBuildSub(parser.builder(), lhs, rhs, CString::new("subtmp").unwrap().into_raw()),
+                "*" => LLVMBuildMul(parser.builder(), lhs, rhs, CString::new("multmp").unwrap().into_raw()),
+                "/" | "%" => build_int_division(parser, &self.op, lhs, rhs),
+                "<" | ">" | "<=" | ">=" | "==" | "!=" => {
+                    LLVMBuildICmp(parser.builder(), int_predicate(&self.op), lhs, rhs, CString::new("cmptmp").unwrap().into_raw())
+                }
+                _ => panic!("invalid binary operation <{}> on int", self.op)
+            };
+        }
+
+        if LLVMTypeOf(lhs) == parser.get_bool_type() && LLVMTypeOf(rhs) == parser.get_bool_type() {
+            return match self.op.as_str() {
+                "==" | "!=" => LLVMBuildICmp(parser.builder(), int_predicate(&self.op), lhs, rhs, CString::new("cmptmp").unwrap().into_raw()),
+                _ => panic!("invalid binary operation <{}> on bool", self.op)
+            };
+        }
+
+        if LLVMTypeOf(lhs) != LLVMTypeOf(rhs) {
+            panic!("mismatched operand types for <{}>: {} and {}", self.op, type_name(LLVMTypeOf(lhs)), type_name(LLVMTypeOf(rhs)));
+        }
+
+        match self.op.as_str() {
+            "+" => LLVMBuildFAdd(parser.builder(), lhs, rhs, CString::new("addtmp").unwrap().into_raw()),
+            "-" => LLVMBuildFSub(parser.builder(), lhs, rhs, CString::new("subtmp").unwrap().into_raw()),
+            "*" => LLVMBuildFMul(parser.builder(), lhs, rhs, CString::new("multmp").unwrap().into_raw()),
+            "/" => LLVMBuildFDiv(parser.builder(), lhs, rhs, CString::new("divtmp").unwrap().into_raw()),
+            "%" => LLVMBuildFRem(parser.builder(), lhs, rhs, CString::new("remtmp").unwrap().into_raw()),
+            "^" => {
+                let function = get_function(parser, "llvm.pow.f64", &[Type::Double, Type::Double], Some(Type::Double));
+                let mut args = vec![lhs, rhs];
+                LLVMBuildCall(parser.builder(), function, args.as_mut_ptr(), args.len() as c_uint, CString::new("powtmp").unwrap().into_raw())
+            }
+            "<" | ">" | "<=" | ">=" | "==" | "!=" => {
+                LLVMBuildFCmp(parser.builder(), real_predicate(&self.op), lhs, rhs, CString::new("cmptmp").unwrap().into_raw())
             }
             _ => panic!("invalid binary operation <{}>", self.op)
         }
     }
 }
 
+// Integer division by zero is undefined behavior in LLVM, and so is the
+// overflow of the minimum integer divided by -1, so the divisor is checked
+// first. A division by zero is reported by the runtime and gives 0. Dividing
+// by -1 negates instead, which wraps around for the minimum integer, and
+// the remainder is 0.
+unsafe fn build_int_division(parser: &mut Parser, op: &str, lhs: LLVMValueRef, rhs: LLVMValueRef) -> LLVMValueRef {
+    let zero = LLVMConstInt(parser.get_int_type(), 0, 1);
+    let minus_one = LLVMConstInt(parser.get_int_type(), -1i64 as u64, 1);
+    let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+    let divide_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("divide").unwrap().into_raw());
+    let error_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("divisionerror").unwrap().into_raw());
+    let negate_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("negate").unwrap().into_raw());
+    let merge_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("dividecont").unwrap().into_raw());
+    let switch = LLVMBuildSwitch(parser.builder(), rhs, divide_block, 2);
+    LLVMAddCase(switch, zero, error_block);
+    LLVMAddCase(switch, minus_one, negate_block);
+
+    LLVMPositionBuilderAtEnd(parser.builder(), negate_block);
+    let negated = if op == "/" {
+        LLVMBuildSub(parser.builder(), zero, lhs, CString::new("negtmp").unwrap().into_raw())
+    } else {
+        zero
+    };
+    LLVMBuildBr(parser.builder(), merge_block);
+
+    LLVMPositionBuilderAtEnd(parser.builder(), error_block);
+    let division_error = get_function(parser, "division_error", &[], None);
+    LLVMBuildCall(parser.builder(), division_error, null_mut(), 0, CString::new("").unwrap().into_raw());
+    LLVMBuildBr(parser.builder(), merge_block);
+
+    LLVMPositionBuilderAtEnd(parser.builder(), divide_block);
+    let value = if op == "/" {
+        LLVMBuildSDiv(parser.builder(), lhs, rhs, CString::new("divtmp").unwrap().into_raw())
+    } else {
+        LLVMBuildSRem(parser.builder(), lhs, rhs, CString::new("remtmp").unwrap().into_raw())
+    };
+    LLVMBuildBr(parser.builder(), merge_block);
+
+    LLVMPositionBuilderAtEnd(parser.builder(), merge_block);
+    let phi = LLVMBuildPhi(parser.builder(), parser.get_int_type(), CString::new("divisiontmp").unwrap().into_raw());
+    let mut values = vec![value, zero, negated];
+    let mut blocks = vec![divide_block, error_block, negate_block];
+    LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), 3);
+    phi
+}
+
+// Fold a constant expression, as accepted by the type checker, into a value.
+// An integer literal takes the given type, if any, like in codegen_with_type.
+unsafe fn build_constant(expr: &Expr, ty: Option<LLVMTypeRef>, parser: &Parser) -> Result<LLVMValueRef, String> {
+    let value = match expr {
+        Expr::IntegerExpr(i) => i.codegen_with_type(ty.unwrap_or(parser.get_double_type())),
+        Expr::NumberExpr(n) => LLVMConstReal(parser.get_double_type(), n.val),
+        Expr::BooleanExpr(b) => LLVMConstInt(parser.get_bool_type(), b.val as u64, 0),
+        Expr::VariableExpr(v) => match parser.get_named_value(v.name.clone()) {
+            Some(NamedValue::Value(value)) => *value,
+            _ => return Err(format!("<{}> is not a constant", v.name))
+        },
+        Expr::UnaryExpr(u) => {
+            let ty = if u.op == '-' && is_untyped(&u.operand) { ty } else { None };
+            let operand = build_constant(&u.operand, ty, parser)?;
+            match u.op {
+                '-' if LLVMTypeOf(operand) == parser.get_int_type() => LLVMConstNeg(operand),
+                '-' => LLVMConstFNeg(operand),
+                '!' => LLVMConstNot(operand),
+                _ => return Err(format!("unknown unary operator <{}>", u.op))
+            }
+        }
+        Expr::BinaryExpr(b) => {
+            // An integer literal takes the type of the other operand.
+            let (lhs, rhs) = if is_untyped(&b.lhs) {
+                let rhs = build_constant(&b.rhs, None, parser)?;
+                (build_constant(&b.lhs, Some(LLVMTypeOf(rhs)), parser)?, rhs)
+            } else {
+                let lhs = build_constant(&b.lhs, None, parser)?;
+                (lhs, build_constant(&b.rhs, Some(LLVMTypeOf(lhs)), parser)?)
+            };
+            build_constant_binary(&b.op, lhs, rhs, parser)?
+        }
+        Expr::CallExpr(c) if c.args.len() == 1 => {
+            let ty = match c.callee_name() {
+                Some("int") => parser.get_int_type(),
+                Some("double") => parser.get_double_type(),
+                Some("bool") => parser.get_bool_type(),
+                _ => return Err("expected a constant expression".to_string())
+            };
+            let literal_type = if ty == parser.get_bool_type() { None } else { Some(ty) };
+            let value = build_constant(&c.args[0], literal_type, parser)?;
+            let from = LLVMTypeOf(value);
+            if from == ty {
+                value
+            } else if ty == parser.get_bool_type() && from == parser.get_int_type() {
+                LLVMConstICmp(LLVMIntPredicate::LLVMIntNE, value, LLVMConstInt(from, 0, 1))
+            } else if ty == parser.get_bool_type() {
+                LLVMConstFCmp(LLVMRealPredicate::LLVMRealONE, value, LLVMConstReal(from, 0.0))
+            } else if from == parser.get_bool_type() && ty == parser.get_int_type() {
+                LLVMConstZExt(value, ty)
+            } else if from == parser.get_bool_type() {
+                LLVMConstUIToFP(value, ty)
+            } else if ty == parser.get_int_type() {
+                LLVMConstFPToSI(value, ty)
+            } else {
+                LLVMConstSIToFP(value, ty)
+            }
+        }
+        _ => return Err("expected a constant expression".to_string())
+    };
+    Ok(value)
+}
+
+unsafe fn build_constant_binary(op: &str, lhs: LLVMValueRef, rhs: LLVMValueRef, parser: &Parser) -> Result<LLVMValueRef, String> {
+    if LLVMTypeOf(lhs) != LLVMTypeOf(rhs) {
+        return Err(format!("mismatched operand types for <{}>: {} and {}", op, type_name(LLVMTypeOf(lhs)), type_name(LLVMTypeOf(rhs))));
+    }
+
+    let value = if LLVMTypeOf(lhs) == parser.get_int_type() {
+        match op {
+            "+" => LLVMConstAdd(lhs, rhs),
+            "-" => LLVMConstSub(lhs, rhs),
+            "*" => LLVMConstMul(lhs, rhs),
+            "/" | "%" if LLVMIsNull(rhs) != 0 => return Err("integer division by zero".to_string()),
+            "/" | "%" if LLVMConstIntGetSExtValue(lhs) == i64::MIN && LLVMConstIntGetSExtValue(rhs) == -1 => {
+                return Err("integer division overflow".to_string())
+            }
+            "/" => LLVMConstSDiv(lhs, rhs),
+            "%" => LLVMConstSRem(lhs, rhs),
+            "<" | ">" | "<=" | ">=" | "==" | "!=" => LLVMConstICmp(int_predicate(op), lhs, rhs),
+            _ => return Err(format!("invalid binary operation <{}> on int", op))
+        }
+    } else if LLVMTypeOf(lhs) == parser.get_bool_type() {
+        match op {
+            "==" | "!=" => LLVMConstICmp(int_predicate(op), lhs, rhs),
+            _ => return Err(format!("invalid binary operation <{}> on bool", op))
+        }
+    } else {
+        match op {
+            "+" => LLVMConstFAdd(lhs, rhs),
+            "-" => LLVMConstFSub(lhs, rhs),
+            "*" => LLVMConstFMul(lhs, rhs),
+            "/" => LLVMConstFDiv(lhs, rhs),
+            "%" => LLVMConstFRem(lhs, rhs),
+            "<" | ">" | "<=" | ">=" | "==" | "!=" => LLVMConstFCmp(real_predicate(op), lhs, rhs),
+            _ => return Err(format!("invalid binary operation <{}>", op))
+        }
+    };
+    Ok(value)
+}
+
+// Operators generated as instructions, the others call user-defined functions.
+pub fn is_builtin_binary(op: &str) -> bool {
+    match op {
+        "=" | "&&" | "||" | "+" | "-" | "*" | "/" | "%" | "^" | "<" | ">" | "<=" | ">=" | "==" | "!=" => true,
+        _ => false
+    }
+}
+
+// Call the function of a user-defined operator, the operands taking the
+// types of its parameters like call arguments.
+unsafe fn build_operator_call(parser: &mut Parser, name: &str, operands: &[&Expr], call_name: &str) -> LLVMValueRef {
+    let function = LLVMGetNamedFunction(parser.module(), CString::new(name).unwrap().into_raw());
+    if function == null_mut() {
+        panic!("invalid operation <{}>", name);
+    }
+
+    let mut args = Vec::new();
+    for (i, operand) in operands.iter().enumerate() {
+        let ty = LLVMTypeOf(LLVMGetParam(function, i as c_uint));
+        args.push(codegen_with_type(operand, ty, parser));
+    }
+    LLVMBuildCall(parser.builder(), function, args.as_mut_ptr(), args.len() as c_uint, CString::new(call_name).unwrap().into_raw())
+}
+
+// Like '<' always did, comparisons are unordered: they also hold when
+// either operand is NaN.
+fn real_predicate(op: &str) -> LLVMRealPredicate {
+    match op {
+        "<" => LLVMRealPredicate::LLVMRealULT,
+        ">" => LLVMRealPredicate::LLVMRealUGT,
+        "<=" => LLVMRealPredicate::LLVMRealULE,
+        ">=" => LLVMRealPredicate::LLVMRealUGE,
+        "==" => LLVMRealPredicate::LLVMRealUEQ,
+        "!=" => LLVMRealPredicate::LLVMRealUNE,
+        _ => panic!("invalid comparison <{}>", op)
+    }
+}
+
+fn int_predicate(op: &str) -> LLVMIntPredicate {
+    match op {
+        "<" => LLVMIntPredicate::LLVMIntSLT,
+        ">" => LLVMIntPredicate::LLVMIntSGT,
+        "<=" => LLVMIntPredicate::LLVMIntSLE,
+        ">=" => LLVMIntPredicate::LLVMIntSGE,
+        "==" => LLVMIntPredicate::LLVMIntEQ,
+        "!=" => LLVMIntPredicate::LLVMIntNE,
+        _ => panic!("invalid comparison <{}>", op)
+    }
+}
+
 // Function call
 #[derive(Debug)]
 pub struct CallExpr {
-    pub callee: String,
+    pub callee: Box<Expr>,
     pub args: Vec<Box<Expr>>,
 }
 
+impl CallExpr {
+    // Name of the callee, when called by name.
+    pub fn callee_name(&self) -> Option<&str> {
+        match *self.callee {
+            Expr::VariableExpr(ref v) => Some(&v.name),
+            _ => None
+        }
+    }
+}
+
 impl AST for CallExpr {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
-        let function = LLVMGetNamedFunction(parser.module(), CString::new(self.callee.clone()).unwrap().into_raw());
-        if function == null_mut() {
-            panic!("unknown function name <{}>", self.callee);
+        // Calls by name go to builtins and functions, unless the name is a
+        // variable holding a function.
+        let name = match self.callee_name() {
+            Some(name) if parser.get_named_value(name.to_string()).is_none() => Some(name),
+            _ => None
+        };
+
+        // Length of a string or array.
+        if name == Some("len") {
+            if self.args.len() != 1 {
+                panic!("invalid param number, expected 1, got {}", self.args.len());
+            }
+
+            let value = self.args[0].codegen(parser);
+            if LLVMTypeOf(value) == parser.get_array_type() {
+                return build_array_field(parser, value, 0, "len");
+            }
+            let strlen = get_function(parser, "strlen", &[Type::Str], Some(Type::Int));
+            let mut args = vec![value];
+            return LLVMBuildCall(parser.builder(), strlen, args.as_mut_ptr(), 1, CString::new("lentmp").unwrap().into_raw());
+        }
+
+        // Conversions between int, double and bool.
+        if name == Some("int") || name == Some("double") || name == Some("bool") {
+            if self.args.len() != 1 {
+                panic!("invalid param number, expected 1, got {}", self.args.len());
+            }
+
+            if name == Some("bool") {
+                let value = self.args[0].codegen(parser);
+                return build_condition(parser, value, "booltmp");
+            }
+
+            let (ty, name) = if name == Some("int") {
+                (parser.get_int_type(), "inttmp")
+            } else {
+                (parser.get_double_type(), "doubletmp")
+            };
+            let value = codegen_with_type(&self.args[0], ty, parser);
+            return if LLVMTypeOf(value) == ty {
+                value
+            } else if LLVMTypeOf(value) == parser.get_bool_type() && ty == parser.get_int_type() {
+                LLVMBuildZExt(parser.builder(), value, ty, CString::new(name).unwrap().into_raw())
+            } else if LLVMTypeOf(value) == parser.get_bool_type() {
+                LLVMBuildUIToFP(parser.builder(), value, ty, CString::new(name).unwrap().into_raw())
+            } else if ty == parser.get_int_type() {
+                LLVMBuildFPToSI(parser.builder(), value, ty, CString::new(name).unwrap().into_raw())
+            } else {
+                LLVMBuildSIToFP(parser.builder(), value, ty, CString::new(name).unwrap().into_raw())
+            };
         }
 
-        if LLVMCountParams(function) != self.args.len() as u32 {
-            panic!("invalid param number, expected {}, got {}", LLVMCountParams(function), self.args.len());
+        // Otherwise, the callee is a function value: a closure, whose function
+        // takes its environment first.
+        let (function, mut args) = match name {
+            Some(name) => {
+                let function = LLVMGetNamedFunction(parser.module(), CString::new(name).unwrap().into_raw());
+                if function == null_mut() {
+                    panic!("unknown function name <{}>", name);
+                }
+                (function, Vec::new())
+            }
+            None => {
+                let closure = self.callee.codegen(parser);
+                let function_pointer = LLVMBuildStructGEP(parser.builder(), closure, 0, CString::new("functionptr").unwrap().into_raw());
+                let env_pointer = LLVMBuildStructGEP(parser.builder(), closure, 1, CString::new("envptr").unwrap().into_raw());
+                let function = LLVMBuildLoad(parser.builder(), function_pointer, CString::new("function").unwrap().into_raw());
+                let env = LLVMBuildLoad(parser.builder(), env_pointer, CString::new("env").unwrap().into_raw());
+                (function, vec![env])
+            }
+        };
+        let function_type = LLVMGetElementType(LLVMTypeOf(function));
+
+        let param_count = LLVMCountParamTypes(function_type) as usize - args.len();
+        if param_count != self.args.len() {
+            panic!("invalid param number, expected {}, got {}", param_count, self.args.len());
         }
+        let mut param_types = vec![null_mut(); param_count + args.len()];
+        LLVMGetParamTypes(function_type, param_types.as_mut_ptr());
+        let param_types = param_types.split_off(args.len());
 
-        let mut args = Vec::new();
-        for arg in self.args.iter() {
-            args.push(arg.codegen(parser))
+        for (arg, ty) in self.args.iter().zip(param_types.into_iter()) {
+            let value = codegen_with_type(arg, ty, parser);
+            if LLVMTypeOf(value) != ty {
+                panic!("invalid argument type for <{}>: expected {}, got {}", name.unwrap_or("function value"), type_name(ty), type_name(LLVMTypeOf(value)));
+            }
+            args.push(value)
         }
 
         LLVMBuildCall(parser.builder(), function, args.as_mut_ptr(), args.len() as c_uint, CString::new("calltmp").unwrap().into_raw())
     }
 }
 
+// Lambda expression, replaced by a ClosureExpr before codegen
+#[derive(Debug)]
+pub struct LambdaExpr {
+    // Unique among the lambdas and nested definitions parsed, naming the
+    // function it is lifted to
+    pub id: usize,
+    pub args: Vec<String>,
+    pub arg_types: Vec<Type>,
+    pub return_type: Type,
+    pub body: Box<Expr>,
+}
+
+// Closure of a lifted lambda, capturing the given variables
+#[derive(Debug)]
+pub struct ClosureExpr {
+    pub function: String,
+    pub captures: Vec<String>,
+}
+
+impl AST for ClosureExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let function = LLVMGetNamedFunction(parser.module(), CString::new(self.function.clone()).unwrap().into_raw());
+        if function == null_mut() {
+            panic!("unknown function name <{}>", self.function);
+        }
+
+        // Copy the captured values into a new environment record, laid out as
+        // the lifted function expects it. Environments are never freed.
+        let env = if self.captures.is_empty() {
+            LLVMConstNull(parser.get_string_type())
+        } else {
+            let values: Vec<LLVMValueRef> = self.captures.iter()
+                .map(|name| VariableExpr { name: name.clone() }.codegen(parser))
+                .collect();
+            let mut field_types: Vec<LLVMTypeRef> = values.iter().map(|value| LLVMTypeOf(*value)).collect();
+            let env_type = LLVMStructTypeInContext(parser.context(), field_types.as_mut_ptr(), field_types.len() as c_uint, 0);
+
+            let env = LLVMBuildMalloc(parser.builder(), env_type, CString::new("env").unwrap().into_raw());
+            for (i, (name, value)) in self.captures.iter().zip(values.into_iter()).enumerate() {
+                let pointer = LLVMBuildStructGEP(parser.builder(), env, i as c_uint, CString::new(format!("{}ptr", name)).unwrap().into_raw());
+                LLVMBuildStore(parser.builder(), value, pointer);
+            }
+            LLVMBuildBitCast(parser.builder(), env, parser.get_string_type(), CString::new("envtmp").unwrap().into_raw())
+        };
+
+        build_closure(parser, function, env)
+    }
+}
+
+// Nested function definition, in scope in its own body and in the
+// expression following it, lifted to the top level before codegen
+#[derive(Debug)]
+pub struct DefExpr {
+    // Unique among the lambdas and nested definitions parsed
+    pub id: usize,
+    pub function: Function,
+    pub body: Box<Expr>,
+}
+
+// Allocate a closure of a function taking its environment first. Closures
+// are never freed either.
+unsafe fn build_closure(parser: &mut Parser, function: LLVMValueRef, env: LLVMValueRef) -> LLVMValueRef {
+    let mut field_types = vec![LLVMTypeOf(function), parser.get_string_type()];
+    let closure_type = LLVMStructTypeInContext(parser.context(), field_types.as_mut_ptr(), 2, 0);
+
+    let closure = LLVMBuildMalloc(parser.builder(), closure_type, CString::new("closure").unwrap().into_raw());
+    let function_pointer = LLVMBuildStructGEP(parser.builder(), closure, 0, CString::new("functionptr").unwrap().into_raw());
+    LLVMBuildStore(parser.builder(), function, function_pointer);
+    let env_pointer = LLVMBuildStructGEP(parser.builder(), closure, 1, CString::new("envptr").unwrap().into_raw());
+    LLVMBuildStore(parser.builder(), env, env_pointer);
+    closure
+}
+
+// The closure of a named function is a constant without environment, whose
+// function drops it and calls the named one. Both are created on first use.
+unsafe fn get_function_closure(parser: &mut Parser, function: LLVMValueRef, name: &str) -> LLVMValueRef {
+    let closure_name = CString::new(format!("{}.closure", name)).unwrap().into_raw();
+    let closure = LLVMGetNamedGlobal(parser.module(), closure_name);
+    if closure != null_mut() {
+        return closure;
+    }
+
+    let function_type = LLVMGetElementType(LLVMTypeOf(function));
+    let param_count = LLVMCountParamTypes(function_type) as usize;
+    let mut param_types = vec![parser.get_string_type(); param_count + 1];
+    LLVMGetParamTypes(function_type, param_types[1..].as_mut_ptr());
+    let adapter_type = LLVMFunctionType(LLVMGetReturnType(function_type), param_types.as_mut_ptr(), param_types.len() as c_uint, 0);
+    let adapter = LLVMAddFunction(parser.module(), CString::new(format!("{}.adapter", name)).unwrap().into_raw(), adapter_type);
+
+    // Generate the adapter aside, then get back to where we were.
+    let insert_block = LLVMGetInsertBlock(parser.builder());
+    let basic_block = LLVMAppendBasicBlockInContext(parser.context(), adapter, CString::new("entry").unwrap().into_raw());
+    LLVMPositionBuilderAtEnd(parser.builder(), basic_block);
+    let mut args: Vec<LLVMValueRef> = (1..=param_count).map(|i| LLVMGetParam(adapter, i as c_uint)).collect();
+    let value = LLVMBuildCall(parser.builder(), function, args.as_mut_ptr(), param_count as c_uint, CString::new("calltmp").unwrap().into_raw());
+    LLVMBuildRet(parser.builder(), value);
+    LLVMPositionBuilderAtEnd(parser.builder(), insert_block);
+
+    let mut fields = vec![adapter, LLVMConstNull(parser.get_string_type())];
+    let initializer = LLVMConstStructInContext(parser.context(), fields.as_mut_ptr(), 2, 0);
+    let closure = LLVMAddGlobal(parser.module(), LLVMTypeOf(initializer), closure_name);
+    LLVMSetInitializer(closure, initializer);
+    LLVMSetGlobalConstant(closure, 1);
+    closure
+}
+
+// If expression
+#[derive(Debug)]
+pub struct IfExpr {
+    pub cond: Box<Expr>,
+    pub then_expr: Box<Expr>,
+    pub else_expr: Box<Expr>,
+}
+
+impl AST for IfExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let cond_value = self.cond.codegen(parser);
+
+        let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+        let then_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("then").unwrap().into_raw());
+        let else_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("else").unwrap().into_raw());
+        let merge_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("ifcont").unwrap().into_raw());
+        LLVMBuildCondBr(parser.builder(), cond_value, then_block, else_block);
+
+        // An integer literal branch takes the type of the other branch, so
+        // that one is generated first.
+        let (then_value, then_block, else_value, else_block) = if is_untyped(&self.then_expr) {
+            let (else_value, else_block) = build_branch(parser, &self.else_expr, else_block, merge_block, None);
+            let (then_value, then_block) = build_branch(parser, &self.then_expr, then_block, merge_block, Some(LLVMTypeOf(else_value)));
+            (then_value, then_block, else_value, else_block)
+        } else {
+            let (then_value, then_block) = build_branch(parser, &self.then_expr, then_block, merge_block, None);
+            let (else_value, else_block) = build_branch(parser, &self.else_expr, else_block, merge_block, Some(LLVMTypeOf(then_value)));
+            (then_value, then_block, else_value, else_block)
+        };
+
+        if LLVMTypeOf(then_value) != LLVMTypeOf(else_value) {
+            panic!("mismatched branch types: {} and {}", type_name(LLVMTypeOf(then_value)), type_name(LLVMTypeOf(else_value)));
+        }
+
+        LLVMPositionBuilderAtEnd(parser.builder(), merge_block);
+        let phi = LLVMBuildPhi(parser.builder(), LLVMTypeOf(then_value), CString::new("iftmp").unwrap().into_raw());
+        let mut values = vec![then_value, else_value];
+        let mut blocks = vec![then_block, else_block];
+        LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+        phi
+    }
+}
+
+// Generate a branch of a conditional into its block, then jump to the merge
+// block. Returns the value and the block it ends in, which codegen of the
+// branch can change.
+unsafe fn build_branch(parser: &mut Parser, expr: &Expr, block: LLVMBasicBlockRef, merge_block: LLVMBasicBlockRef, ty: Option<LLVMTypeRef>) -> (LLVMValueRef, LLVMBasicBlockRef) {
+    LLVMPositionBuilderAtEnd(parser.builder(), block);
+    let value = match ty {
+        Some(ty) => codegen_with_type(expr, ty, parser),
+        None => expr.codegen(parser),
+    };
+    LLVMBuildBr(parser.builder(), merge_block);
+    (value, LLVMGetInsertBlock(parser.builder()))
+}
+
+// For loop
+#[derive(Debug)]
+pub struct ForExpr {
+    pub var_name: String,
+    pub start: Box<Expr>,
+    pub end: Box<Expr>,
+    pub step: Option<Box<Expr>>,
+    pub body: Box<Expr>,
+}
+
+impl AST for ForExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+
+        // Emit the start code first, without the variable in scope. The
+        // variable has the type of its start value.
+        let start_value = self.start.codegen(parser);
+        let ty = LLVMTypeOf(start_value);
+        let alloca = parser.create_entry_block_alloca(function, &self.var_name, ty);
+        LLVMBuildStore(parser.builder(), start_value, alloca);
+
+        let loop_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("loop").unwrap().into_raw());
+        let step_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("step").unwrap().into_raw());
+        let after_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("afterloop").unwrap().into_raw());
+        LLVMBuildBr(parser.builder(), loop_block);
+        LLVMPositionBuilderAtEnd(parser.builder(), loop_block);
+
+        // Shadow any outer binding of the same name while in the loop body.
+        let old_value = parser.insert_named_value(self.var_name.clone(), NamedValue::Variable(alloca));
+
+        // 'continue' goes on with the next step, 'break' leaves the loop.
+        parser.push_loop(step_block, after_block);
+        self.body.codegen(parser);
+        parser.pop_loop();
+        LLVMBuildBr(parser.builder(), step_block);
+        LLVMPositionBuilderAtEnd(parser.builder(), step_block);
+
+        let step_value = match self.step {
+            Some(ref step) => codegen_with_type(step, ty, parser),
+            None => IntegerExpr { val: 1 }.codegen_with_type(ty),
+        };
+        if LLVMTypeOf(step_value) != ty {
+            panic!("invalid step type for <{}>: expected {}, got {}", self.var_name, type_name(ty), type_name(LLVMTypeOf(step_value)));
+        }
+
+        let end_cond = self.end.codegen(parser);
+
+        // The body may have changed the variable, so reload it before stepping.
+        let current_value = LLVMBuildLoad(parser.builder(), alloca, CString::new(self.var_name.clone()).unwrap().into_raw());
+        let next_value = if ty == parser.get_int_type() {
+            LLVMBuildAdd(parser.builder(), current_value, step_value, CString::new("nextvar").unwrap().into_raw())
+        } else {
+            LLVMBuildFAdd(parser.builder(), current_value, step_value, CString::new("nextvar").unwrap().into_raw())
+        };
+        LLVMBuildStore(parser.builder(), next_value, alloca);
+
+        LLVMBuildCondBr(parser.builder(), end_cond, loop_block, after_block);
+        LLVMPositionBuilderAtEnd(parser.builder(), after_block);
+
+        // Restore the outer binding.
+        match old_value {
+            Some(value) => parser.insert_named_value(self.var_name.clone(), value),
+            None => parser.remove_named_value(self.var_name.clone()),
+        };
+
+        // A for loop always evaluates to 0.0.
+        LLVMConstReal(parser.get_double_type(), 0.0)
+    }
+}
+
+// Immutable binding
+#[derive(Debug)]
+pub struct LetExpr {
+    // A single name binds the whole value, several destructure a tuple.
+    pub names: Vec<String>,
+    pub value: Box<Expr>,
+    pub body: Box<Expr>,
+}
+
+impl AST for LetExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // The values are bound as is, no stack slot is needed.
+        let value = self.value.codegen(parser);
+        let mut old_values = Vec::new();
+        for (i, name) in self.names.iter().enumerate() {
+            let value = if self.names.len() == 1 {
+                value
+            } else {
+                LLVMBuildExtractValue(parser.builder(), value, i as c_uint, CString::new(name.clone()).unwrap().into_raw())
+            };
+            old_values.push(parser.insert_named_value(name.clone(), NamedValue::Value(value)));
+        }
+
+        let body = self.body.codegen(parser);
+
+        // Restore the outer bindings.
+        for (name, old_value) in self.names.iter().zip(old_values.into_iter()) {
+            match old_value {
+                Some(value) => parser.insert_named_value(name.clone(), value),
+                None => parser.remove_named_value(name.clone()),
+            };
+        }
+
+        body
+    }
+}
+
+// While loop
+#[derive(Debug)]
+pub struct WhileExpr {
+    pub cond: Box<Expr>,
+    pub body: Box<Expr>,
+}
+
+impl AST for WhileExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+        let cond_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("whilecond").unwrap().into_raw());
+        let body_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("whilebody").unwrap().into_raw());
+        let after_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("afterwhile").unwrap().into_raw());
+        LLVMBuildBr(parser.builder(), cond_block);
+
+        LLVMPositionBuilderAtEnd(parser.builder(), cond_block);
+        let cond_value = self.cond.codegen(parser);
+        LLVMBuildCondBr(parser.builder(), cond_value, body_block, after_block);
+
+        // 'continue' re-evaluates the condition, 'break' leaves the loop.
+        LLVMPositionBuilderAtEnd(parser.builder(), body_block);
+        parser.push_loop(cond_block, after_block);
+        self.body.codegen(parser);
+        parser.pop_loop();
+        LLVMBuildBr(parser.builder(), cond_block);
+
+        LLVMPositionBuilderAtEnd(parser.builder(), after_block);
+
+        // A while loop always evaluates to 0.0.
+        LLVMConstReal(parser.get_double_type(), 0.0)
+    }
+}
+
+// Break out of the innermost loop
+#[derive(Debug)]
+pub struct BreakExpr;
+
+impl AST for BreakExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let after_block = match parser.current_loop() {
+            Some((_, after_block)) => after_block,
+            None => panic!("'break' outside of a loop")
+        };
+        build_jump(parser, after_block, "afterbreak");
+        LLVMConstReal(parser.get_double_type(), 0.0)
+    }
+}
+
+// Go on with the next iteration of the innermost loop
+#[derive(Debug)]
+pub struct ContinueExpr;
+
+impl AST for ContinueExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let continue_block = match parser.current_loop() {
+            Some((continue_block, _)) => continue_block,
+            None => panic!("'continue' outside of a loop")
+        };
+        build_jump(parser, continue_block, "aftercontinue");
+        LLVMConstReal(parser.get_double_type(), 0.0)
+    }
+}
+
+// Branch to the target block. Anything emitted after the jump goes to a
+// fresh, unreachable block, which is cleaned up by the optimization passes.
+unsafe fn build_jump(parser: &mut Parser, target: LLVMBasicBlockRef, name: &str) {
+    let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+    LLVMBuildBr(parser.builder(), target);
+
+    let dead_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new(name).unwrap().into_raw());
+    LLVMPositionBuilderAtEnd(parser.builder(), dead_block);
+}
+
+// Integer literals, and jumps which never produce a value, have no type of
+// their own: they take the type of the value they are used with.
+pub fn is_untyped(expr: &Expr) -> bool {
+    match expr {
+        Expr::IntegerExpr(_) | Expr::BreakExpr(_) | Expr::ContinueExpr(_) => true,
+        Expr::UnaryExpr(u) => u.op == '-' && is_untyped(&u.operand),
+        _ => false
+    }
+}
+
+unsafe fn codegen_with_type(expr: &Expr, ty: LLVMTypeRef, parser: &mut Parser) -> LLVMValueRef {
+    match expr {
+        Expr::IntegerExpr(i) => i.codegen_with_type(ty),
+        Expr::UnaryExpr(u) if u.op == '-' && is_untyped(&u.operand) => {
+            let operand = codegen_with_type(&u.operand, ty, parser);
+            if LLVMTypeOf(operand) == parser.get_int_type() {
+                LLVMConstNeg(operand)
+            } else {
+                LLVMConstFNeg(operand)
+            }
+        }
+        Expr::BreakExpr(b) => {
+            b.codegen(parser);
+            LLVMGetUndef(ty)
+        }
+        Expr::ContinueExpr(c) => {
+            c.codegen(parser);
+            LLVMGetUndef(ty)
+        }
+        Expr::TupleExpr(t) if LLVMGetTypeKind(ty) == LLVMTypeKind::LLVMStructTypeKind
+            && LLVMCountStructElementTypes(ty) as usize == t.elements.len() => t.codegen_with_type(ty, parser),
+        _ => expr.codegen(parser)
+    }
+}
+
+// Convert a value to bool: a number is true when it is not zero.
+unsafe fn build_condition(parser: &mut Parser, value: LLVMValueRef, name: &str) -> LLVMValueRef {
+    if LLVMTypeOf(value) == parser.get_bool_type() {
+        value
+    } else if LLVMTypeOf(value) == parser.get_int_type() {
+        let zero = LLVMConstInt(parser.get_int_type(), 0, 1);
+        LLVMBuildICmp(parser.builder(), LLVMIntPredicate::LLVMIntNE, value, zero, CString::new(name).unwrap().into_raw())
+    } else {
+        let zero = LLVMConstReal(parser.get_double_type(), 0.0);
+        LLVMBuildFCmp(parser.builder(), LLVMRealPredicate::LLVMRealONE, value, zero, CString::new(name).unwrap().into_raw())
+    }
+}
+
+unsafe fn type_name(ty: LLVMTypeRef) -> String {
+    CStr::from_ptr(LLVMPrintTypeToString(ty)).to_str().unwrap().to_owned()
+}
+
+// Mutable variables
+#[derive(Debug)]
+pub struct VarExpr {
+    pub vars: Vec<(String, Option<Box<Expr>>)>,
+    pub body: Box<Expr>,
+}
+
+impl AST for VarExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(parser.builder()));
+
+        let mut old_values = Vec::new();
+        for (name, init) in self.vars.iter() {
+            // Emit the initializer before adding the variable to scope, so
+            // that 'var a = a in ...' refers to an outer 'a'.
+            let init_value = match init {
+                Some(init) => init.codegen(parser),
+                None => LLVMConstReal(parser.get_double_type(), 0.0),
+            };
+
+            let alloca = parser.create_entry_block_alloca(function, name, LLVMTypeOf(init_value));
+            LLVMBuildStore(parser.builder(), init_value, alloca);
+
+            old_values.push((name.clone(), parser.insert_named_value(name.clone(), NamedValue::Variable(alloca))));
+        }
+
+        let body = self.body.codegen(parser);
+
+        // Restore the outer bindings, innermost first.
+        for (name, old_value) in old_values.into_iter().rev() {
+            match old_value {
+                Some(value) => parser.insert_named_value(name, value),
+                None => parser.remove_named_value(name),
+            };
+        }
+
+        body
+    }
+}
+
+// Block of expressions, evaluated in order
+#[derive(Debug)]
+pub struct BlockExpr {
+    pub exprs: Vec<Box<Expr>>,
+}
+
+impl AST for BlockExpr {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // An empty block evaluates to 0.0, otherwise to its last expression.
+        let mut value = LLVMConstReal(parser.get_double_type(), 0.0);
+        for expr in self.exprs.iter() {
+            value = expr.codegen(parser);
+        }
+        value
+    }
+}
+
 // Function prototype
 #[derive(Debug)]
 pub struct Prototype {
     pub name: String,
     pub args: Vec<String>,
+    pub arg_types: Vec<Type>,
+    pub return_type: Type,
+    // Variables captured by a lifted lambda, which it takes in an
+    // environment record before the other arguments
+    pub env: Option<Vec<(String, Type)>>,
+}
+
+impl Prototype {
+    // Prototype of the function wrapping a top-level expression
+    pub fn anonymous(return_type: Type) -> Prototype {
+        Prototype {
+            name: "".to_string(),
+            args: vec![],
+            arg_types: vec![],
+            return_type: return_type,
+            env: None,
+        }
+    }
+}
+
+impl fmt::Display for Prototype {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (i, (arg, ty)) in self.args.iter().zip(self.arg_types.iter()).enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", arg, ty)?;
+        }
+        write!(f, "): {}", self.return_type)
+    }
+}
+
+impl Prototype {
+    fn function_type(&self, parser: &Parser) -> LLVMTypeRef {
+        match self.env {
+            Some(_) => parser.get_closure_function_type(&self.arg_types, &self.return_type),
+            None => parser.get_function_type(&self.arg_types, &self.return_type),
+        }
+    }
 }
 
 impl AST for Prototype {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
-        let function_type = parser.get_function_type(self.args.len());
+        let function_type = self.function_type(parser);
         let function = LLVMAddFunction(parser.module(), CString::new(self.name.clone()).unwrap().into_raw(), function_type);
 //        if LLVMCountBasicBlocks(function) != 0 {
 //            panic!("redefinition of function");
 //        }
 
+        let offset = if self.env.is_some() {
+            LLVMSetValueName2(LLVMGetParam(function, 0), CString::new("env").unwrap().into_raw(), 3);
+            1
+        } else {
+            0
+        };
         for (i, arg) in self.args.iter().enumerate() {
-            let function_arg = LLVMGetParam(function, i as c_uint);
+            let function_arg = LLVMGetParam(function, (i + offset) as c_uint);
             LLVMSetValueName2(function_arg, CString::new(arg.clone()).unwrap().into_raw(), arg.len());
-            parser.insert_named_value(arg.clone(), function_arg);
         }
 
         function
@@ -149,10 +1364,51 @@ impl AST for Function {
     unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
         parser.clear_named_value();
 
-        let function = self.proto.codegen(parser);
+        // Define the function declared already if any, as the functions
+        // lifted out of an item are, since they may refer to each other.
+        let function = LLVMGetNamedFunction(parser.module(), CString::new(self.proto.name.clone()).unwrap().into_raw());
+        let function = if function != null_mut() && LLVMCountBasicBlocks(function) == 0
+            && LLVMGetElementType(LLVMTypeOf(function)) == self.proto.function_type(parser) {
+            function
+        } else {
+            self.proto.codegen(parser)
+        };
         let basic_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("entry").unwrap().into_raw());
         LLVMPositionBuilderAtEnd(parser.builder(), basic_block);
-        let body = self.body.codegen(parser);
+
+        // The captured variables of a lifted lambda are copied out of its
+        // environment, like arguments.
+        let offset = match self.proto.env {
+            Some(ref env) => {
+                let mut field_types: Vec<LLVMTypeRef> = env.iter().map(|(_, ty)| parser.get_type(ty)).collect();
+                let env_type = LLVMStructTypeInContext(parser.context(), field_types.as_mut_ptr(), field_types.len() as c_uint, 0);
+                let record = LLVMBuildBitCast(parser.builder(), LLVMGetParam(function, 0), LLVMPointerType(env_type, 0), CString::new("record").unwrap().into_raw());
+
+                for (i, (name, _)) in env.iter().enumerate() {
+                    let pointer = LLVMBuildStructGEP(parser.builder(), record, i as c_uint, CString::new(format!("{}ptr", name)).unwrap().into_raw());
+                    let value = LLVMBuildLoad(parser.builder(), pointer, CString::new(name.clone()).unwrap().into_raw());
+                    let alloca = parser.create_entry_block_alloca(function, name, LLVMTypeOf(value));
+                    LLVMBuildStore(parser.builder(), value, alloca);
+                    parser.insert_named_value(name.clone(), NamedValue::Variable(alloca));
+                }
+                1
+            }
+            None => 0,
+        };
+
+        // Spill the arguments into stack slots so that they can be mutated.
+        for (i, arg) in self.proto.args.iter().enumerate() {
+            let param = LLVMGetParam(function, (i + offset) as c_uint);
+            let alloca = parser.create_entry_block_alloca(function, arg, LLVMTypeOf(param));
+            LLVMBuildStore(parser.builder(), param, alloca);
+            parser.insert_named_value(arg.clone(), NamedValue::Variable(alloca));
+        }
+
+        let return_type = LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(function)));
+        let body = codegen_with_type(&self.body, return_type, parser);
+        if LLVMTypeOf(body) != return_type {
+            panic!("invalid return type for <{}>: expected {}, got {}", self.proto.name, type_name(return_type), type_name(LLVMTypeOf(body)));
+        }
         LLVMBuildRet(parser.builder(), body);
 
         if LLVMVerifyFunction(function, LLVMVerifierFailureAction::LLVMPrintMessageAction) != 0 {
@@ -163,3 +1419,81 @@ impl AST for Function {
         function
     }
 }
+
+// Struct definition
+#[derive(Debug)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+impl AST for StructDef {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // All the fields are doubles.
+        let struct_type = LLVMStructCreateNamed(parser.context(), CString::new(self.name.clone()).unwrap().into_raw());
+        let mut field_types = vec![parser.get_double_type(); self.fields.len()];
+        LLVMStructSetBody(struct_type, field_types.as_mut_ptr(), field_types.len() as c_uint, 0);
+
+        // The constructor, named after the struct, takes the fields in order
+        // and allocates the struct on the heap. Structs are never freed.
+        let proto = Prototype {
+            name: self.name.clone(),
+            args: self.fields.clone(),
+            arg_types: vec![Type::Double; self.fields.len()],
+            return_type: Type::Struct(self.name.clone()),
+            env: None,
+        };
+        let function = proto.codegen(parser);
+        let basic_block = LLVMAppendBasicBlockInContext(parser.context(), function, CString::new("entry").unwrap().into_raw());
+        LLVMPositionBuilderAtEnd(parser.builder(), basic_block);
+
+        let object = LLVMBuildMalloc(parser.builder(), struct_type, CString::new("object").unwrap().into_raw());
+        for (i, field) in self.fields.iter().enumerate() {
+            let pointer = LLVMBuildStructGEP(parser.builder(), object, i as c_uint, CString::new(format!("{}ptr", field)).unwrap().into_raw());
+            LLVMBuildStore(parser.builder(), LLVMGetParam(function, i as c_uint), pointer);
+        }
+        LLVMBuildRet(parser.builder(), object);
+
+        if LLVMVerifyFunction(function, LLVMVerifierFailureAction::LLVMPrintMessageAction) != 0 {
+            panic!("function verify failed");
+        }
+        function
+    }
+}
+
+// Global variable or constant definition
+#[derive(Debug)]
+pub struct GlobalDef {
+    pub name: String,
+    pub value: Box<Expr>,
+    pub constant: bool,
+}
+
+impl GlobalDef {
+    // Compute the value at compile time. It is not generated with the
+    // builder, which may be positioned in another function.
+    pub fn evaluate(&self, parser: &Parser) -> Result<LLVMValueRef, String> {
+        unsafe { build_constant(&self.value, None, parser) }
+    }
+}
+
+impl AST for GlobalDef {
+    unsafe fn codegen(&self, parser: &mut Parser) -> LLVMValueRef {
+        // The value was computed once already when the global was checked.
+        let value = match self.evaluate(parser) {
+            Ok(value) => value,
+            Err(message) => panic!("invalid value for <{}>: {}", self.name, message)
+        };
+
+        // Constants are folded into their uses, global variables are loaded
+        // and stored like local ones.
+        if self.constant {
+            parser.insert_global(self.name.clone(), NamedValue::Value(value));
+            return value;
+        }
+        let global = LLVMAddGlobal(parser.module(), LLVMTypeOf(value), CString::new(self.name.clone()).unwrap().into_raw());
+        LLVMSetInitializer(global, value);
+        parser.insert_global(self.name.clone(), NamedValue::Variable(global));
+        global
+    }
+}
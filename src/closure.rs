@@ -0,0 +1,329 @@
+use std::mem;
+
+use crate::ast::{Type, Expr, VariableExpr, CallExpr, ClosureExpr, BlockExpr, Prototype, Function};
+use crate::typeck::TypeChecker;
+
+// Closure conversion, run on each definition and top-level expression once
+// type checked, before codegen.
+//
+// A lambda is lifted into a function of its own, named after its id, which
+// takes the variables it captures from the enclosing scopes in an
+// environment record, passed before the other arguments. The lambda is
+// replaced by a ClosureExpr, which copies the captured values into a new
+// environment and pairs it with the function. Variables are captured by
+// value, so the type checker rejects assigning a captured variable, both in
+// the lambda and in the enclosing scopes once captured.
+//
+// A nested definition is lambda lifted instead: the variables it uses from
+// the enclosing scopes become extra arguments, before its own, and each call
+// to it passes them, so it cannot assign them either. Where it is used as a
+// value, it is replaced by a closure of an adapter taking them in an
+// environment, which captures them like a lambda.
+//
+// The lifted functions are returned in no particular order, to be declared
+// before any is generated.
+pub fn convert(expr: &mut Expr, type_checker: &mut TypeChecker) -> Vec<Function> {
+    let mut functions = Vec::new();
+    convert_expr(expr, type_checker, &mut functions);
+    functions
+}
+
+fn convert_expr(expr: &mut Expr, type_checker: &mut TypeChecker, functions: &mut Vec<Function>) {
+    match expr {
+        Expr::LambdaExpr(_) => convert_lambda(expr, type_checker, functions),
+        Expr::DefExpr(_) => convert_definition(expr, type_checker, functions),
+        _ => {
+            for child in children_mut(expr) {
+                convert_expr(child, type_checker, functions);
+            }
+        }
+    }
+}
+
+fn convert_lambda(expr: &mut Expr, type_checker: &mut TypeChecker, functions: &mut Vec<Function>) {
+    let (name, captures) = match expr {
+        Expr::LambdaExpr(lambda) => {
+            let scope = type_checker.take_scope(lambda.id);
+            (format!("lambda.{}", lambda.id), captures(&lambda.body, &lambda.args, scope))
+        }
+        _ => unreachable!(),
+    };
+
+    let closure = Expr::ClosureExpr(ClosureExpr {
+        function: name.clone(),
+        captures: captures.iter().map(|(name, _)| name.clone()).collect(),
+    });
+    let mut lambda = match mem::replace(expr, closure) {
+        Expr::LambdaExpr(lambda) => lambda,
+        _ => unreachable!(),
+    };
+
+    convert_expr(&mut lambda.body, type_checker, functions);
+    functions.push(Function {
+        proto: Box::new(Prototype {
+            name: name,
+            args: lambda.args,
+            arg_types: lambda.arg_types.iter().map(|ty| type_checker.resolve_type(ty)).collect(),
+            return_type: type_checker.resolve_type(&lambda.return_type),
+            env: Some(captures),
+        }),
+        body: lambda.body,
+    });
+}
+
+fn convert_definition(expr: &mut Expr, type_checker: &mut TypeChecker, functions: &mut Vec<Function>) {
+    // The nested definition gives way to the expression it is in scope in.
+    let mut def = match mem::replace(expr, Expr::BlockExpr(BlockExpr { exprs: Vec::new() })) {
+        Expr::DefExpr(def) => def,
+        _ => unreachable!(),
+    };
+    let proto = &def.function.proto;
+
+    let mut bound = vec![proto.name.clone()];
+    bound.extend(proto.args.iter().cloned());
+    let captures = captures(&def.function.body, &bound, type_checker.take_scope(def.id));
+
+    let lifted = Lifted {
+        name: proto.name.clone(),
+        function: format!("{}.{}", proto.name, def.id),
+        adapter: format!("{}.{}.adapter", proto.name, def.id),
+        captures: captures.iter().map(|(name, _)| name.clone()).collect(),
+    };
+    let mut used_as_value = false;
+    if !proto.args.contains(&lifted.name) {
+        used_as_value |= replace_references(&mut def.function.body, &lifted);
+    }
+    used_as_value |= replace_references(&mut def.body, &lifted);
+
+    let arg_types: Vec<Type> = proto.arg_types.iter().map(|ty| type_checker.resolve_type(ty)).collect();
+    let return_type = type_checker.resolve_type(&proto.return_type);
+
+    if used_as_value {
+        functions.push(Function {
+            proto: Box::new(Prototype {
+                name: lifted.adapter.clone(),
+                args: proto.args.clone(),
+                arg_types: arg_types.clone(),
+                return_type: return_type.clone(),
+                env: Some(captures.clone()),
+            }),
+            body: Box::new(Expr::CallExpr(CallExpr {
+                callee: variable(&lifted.function),
+                args: lifted.captures.iter().chain(proto.args.iter()).map(|name| variable(name)).collect(),
+            })),
+        });
+    }
+
+    let proto = Prototype {
+        name: lifted.function,
+        args: lifted.captures.iter().chain(proto.args.iter()).cloned().collect(),
+        arg_types: captures.into_iter().map(|(_, ty)| ty).chain(arg_types.into_iter()).collect(),
+        return_type: return_type,
+        env: None,
+    };
+
+    convert_expr(&mut def.function.body, type_checker, functions);
+    functions.push(Function {
+        proto: Box::new(proto),
+        body: def.function.body,
+    });
+
+    convert_expr(&mut def.body, type_checker, functions);
+    *expr = *def.body;
+}
+
+// The variables a function body uses from the enclosing scopes, with their
+// types.
+fn captures(body: &Expr, bound: &[String], scope: Vec<(String, Type)>) -> Vec<(String, Type)> {
+    free_variables(body, bound).iter()
+        .filter_map(|name| scope.iter().rev().find(|(n, _)| n == name).cloned())
+        .collect()
+}
+
+// A nested function, and what it is lifted to
+struct Lifted {
+    name: String,
+    function: String,
+    adapter: String,
+    captures: Vec<String>,
+}
+
+// Replace the references to a nested function, where not shadowed, by calls
+// to the lifted function or closures of the adapter. Returns whether there
+// are any of the latter.
+fn replace_references(expr: &mut Expr, lifted: &Lifted) -> bool {
+    match expr {
+        Expr::CallExpr(c) if c.callee_name() == Some(&lifted.name) => {
+            let used_as_value = c.args.iter_mut().fold(false, |used, arg| replace_references(arg, lifted) | used);
+            let mut args: Vec<Box<Expr>> = lifted.captures.iter().map(|name| variable(name)).collect();
+            args.append(&mut c.args);
+            c.callee = variable(&lifted.function);
+            c.args = args;
+            used_as_value
+        }
+        Expr::VariableExpr(v) if v.name == lifted.name => {
+            *expr = Expr::ClosureExpr(ClosureExpr {
+                function: lifted.adapter.clone(),
+                captures: lifted.captures.clone(),
+            });
+            true
+        }
+        Expr::ForExpr(f) if f.var_name == lifted.name => replace_references(&mut f.start, lifted),
+        Expr::VarExpr(v) if v.vars.iter().any(|(name, _)| *name == lifted.name) => {
+            let mut used_as_value = false;
+            for (name, init) in v.vars.iter_mut() {
+                if let Some(init) = init {
+                    used_as_value |= replace_references(init, lifted);
+                }
+                if *name == lifted.name {
+                    break;
+                }
+            }
+            used_as_value
+        }
+        Expr::LetExpr(l) if l.names.contains(&lifted.name) => replace_references(&mut l.value, lifted),
+        Expr::LambdaExpr(l) if l.args.contains(&lifted.name) => false,
+        Expr::DefExpr(d) if d.function.proto.name == lifted.name => false,
+        Expr::DefExpr(d) if d.function.proto.args.contains(&lifted.name) => replace_references(&mut d.body, lifted),
+        _ => children_mut(expr).into_iter().fold(false, |used, child| replace_references(child, lifted) | used),
+    }
+}
+
+fn variable(name: &str) -> Box<Expr> {
+    Box::new(Expr::VariableExpr(VariableExpr { name: name.to_string() }))
+}
+
+// The variables an expression uses without binding them, in order of first
+// use. Those a nested function uses count as used where it is defined.
+pub fn free_variables(expr: &Expr, bound: &[String]) -> Vec<String> {
+    let mut free = Vec::new();
+    collect_free_variables(expr, &mut bound.to_vec(), &mut free);
+    free
+}
+
+fn collect_free_variables(expr: &Expr, bound: &mut Vec<String>, free: &mut Vec<String>) {
+    let len = bound.len();
+    match expr {
+        Expr::VariableExpr(v) => use_variable(&v.name, bound, free),
+        Expr::ClosureExpr(c) => {
+            for name in c.captures.iter() {
+                use_variable(name, bound, free);
+            }
+        }
+        Expr::ForExpr(f) => {
+            collect_free_variables(&f.start, bound, free);
+            bound.push(f.var_name.clone());
+            collect_free_variables(&f.end, bound, free);
+            if let Some(ref step) = f.step {
+                collect_free_variables(step, bound, free);
+            }
+            collect_free_variables(&f.body, bound, free);
+        }
+        Expr::VarExpr(v) => {
+            for (name, init) in v.vars.iter() {
+                if let Some(init) = init {
+                    collect_free_variables(init, bound, free);
+                }
+                bound.push(name.clone());
+            }
+            collect_free_variables(&v.body, bound, free);
+        }
+        Expr::LetExpr(l) => {
+            collect_free_variables(&l.value, bound, free);
+            bound.extend(l.names.iter().cloned());
+            collect_free_variables(&l.body, bound, free);
+        }
+        Expr::LambdaExpr(l) => {
+            bound.extend(l.args.iter().cloned());
+            collect_free_variables(&l.body, bound, free);
+        }
+        Expr::DefExpr(d) => {
+            bound.push(d.function.proto.name.clone());
+            bound.extend(d.function.proto.args.iter().cloned());
+            collect_free_variables(&d.function.body, bound, free);
+            bound.truncate(len + 1);
+            collect_free_variables(&d.body, bound, free);
+        }
+        _ => {
+            for child in children(expr) {
+                collect_free_variables(child, bound, free);
+            }
+        }
+    }
+    bound.truncate(len);
+}
+
+fn use_variable(name: &str, bound: &[String], free: &mut Vec<String>) {
+    if !bound.iter().any(|n| n == name) && !free.iter().any(|n| n == name) {
+        free.push(name.to_string());
+    }
+}
+
+// The subexpressions of an expression, in evaluation order.
+fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::ArrayExpr(a) => a.elements.iter().map(|e| &**e).collect(),
+        Expr::TupleExpr(t) => t.elements.iter().map(|e| &**e).collect(),
+        Expr::IndexExpr(i) => vec![&i.array, &i.index],
+        Expr::FieldExpr(f) => vec![&f.object],
+        Expr::UnaryExpr(u) => vec![&u.operand],
+        Expr::BinaryExpr(b) => vec![&b.lhs, &b.rhs],
+        Expr::CallExpr(c) => {
+            let mut children = vec![&*c.callee];
+            children.extend(c.args.iter().map(|arg| &**arg));
+            children
+        }
+        Expr::LambdaExpr(l) => vec![&l.body],
+        Expr::DefExpr(d) => vec![&d.function.body, &d.body],
+        Expr::IfExpr(i) => vec![&i.cond, &i.then_expr, &i.else_expr],
+        Expr::ForExpr(f) => {
+            let mut children = vec![&*f.start, &*f.end];
+            children.extend(f.step.iter().map(|step| &**step));
+            children.push(&f.body);
+            children
+        }
+        Expr::VarExpr(v) => {
+            let mut children: Vec<&Expr> = v.vars.iter().filter_map(|(_, init)| init.as_ref().map(|init| &**init)).collect();
+            children.push(&v.body);
+            children
+        }
+        Expr::LetExpr(l) => vec![&l.value, &l.body],
+        Expr::WhileExpr(w) => vec![&w.cond, &w.body],
+        Expr::BlockExpr(b) => b.exprs.iter().map(|e| &**e).collect(),
+        _ => vec![],
+    }
+}
+
+fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::ArrayExpr(a) => a.elements.iter_mut().map(|e| &mut **e).collect(),
+        Expr::TupleExpr(t) => t.elements.iter_mut().map(|e| &mut **e).collect(),
+        Expr::IndexExpr(i) => vec![&mut i.array, &mut i.index],
+        Expr::FieldExpr(f) => vec![&mut f.object],
+        Expr::UnaryExpr(u) => vec![&mut u.operand],
+        Expr::BinaryExpr(b) => vec![&mut b.lhs, &mut b.rhs],
+        Expr::CallExpr(c) => {
+            let mut children = vec![&mut *c.callee];
+            children.extend(c.args.iter_mut().map(|arg| &mut **arg));
+            children
+        }
+        Expr::LambdaExpr(l) => vec![&mut l.body],
+        Expr::DefExpr(d) => vec![&mut d.function.body, &mut d.body],
+        Expr::IfExpr(i) => vec![&mut i.cond, &mut i.then_expr, &mut i.else_expr],
+        Expr::ForExpr(f) => {
+            let mut children = vec![&mut *f.start, &mut *f.end];
+            children.extend(f.step.iter_mut().map(|step| &mut **step));
+            children.push(&mut f.body);
+            children
+        }
+        Expr::VarExpr(v) => {
+            let mut children: Vec<&mut Expr> = v.vars.iter_mut().filter_map(|(_, init)| init.as_mut().map(|init| &mut **init)).collect();
+            children.push(&mut v.body);
+            children
+        }
+        Expr::LetExpr(l) => vec![&mut l.value, &mut l.body],
+        Expr::WhileExpr(w) => vec![&mut w.cond, &mut w.body],
+        Expr::BlockExpr(b) => b.exprs.iter_mut().map(|e| &mut **e).collect(),
+        _ => vec![],
+    }
+}
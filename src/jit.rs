@@ -1,12 +1,17 @@
 use std::io::{self, Write};
-use std::ffi::CString;
+use std::ffi::{CString, CStr};
+use std::os::raw::{c_char, c_uint};
+use std::ptr::null_mut;
 
-use llvm::core::LLVMDumpValue;
+use llvm::prelude::*;
+use llvm::core::*;
 use llvm::execution_engine::*;
+use llvm::target::LLVMOffsetOfElement;
 
 use crate::lexer::Token;
 use crate::parser::Parser;
-use crate::ast::{AST, Function, Prototype};
+use crate::ast::{AST, Type, Expr, Function, Prototype};
+use crate::runtime;
 
 pub struct JIT<'b> {
     parser: Parser<'b>,
@@ -16,6 +21,7 @@ pub struct JIT<'b> {
 impl<'b> JIT<'b> {
     pub fn new(buf: &'b str) -> JIT<'b> {
         let parser = Parser::new(buf);
+        runtime::register();
         let execution_engine = unsafe {
             LLVMLinkInMCJIT();
             let mut execution_engine: LLVMExecutionEngineRef = 0 as LLVMExecutionEngineRef;
@@ -41,36 +47,183 @@ impl<'b> JIT<'b> {
             match self.parser.token() {
                 None => break,
                 Some(Token::Def) => {
-                    let def = self.parser.parse_definition();
-                    println!("Parsed a definition");
+                    let mut def = self.parser.parse_definition();
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    self.parser.check_function(&mut def);
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    println!("Parsed a definition: {}", def.proto);
+                    self.generate_closures(&mut def.body);
                     unsafe {
                         LLVMDumpValue(def.codegen(&mut self.parser));
                     }
                 }
                 Some(Token::Extern) => {
-                    let ext = self.parser.parse_extern();
+                    let mut ext = self.parser.parse_extern();
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    self.parser.check_extern(&mut ext);
+                    if self.report_diagnostics() {
+                        continue;
+                    }
                     println!("Parsed an extern");
                     unsafe {
                         LLVMDumpValue(ext.codegen(&mut self.parser));
                     }
                 }
+                Some(Token::Struct) => {
+                    let def = self.parser.parse_struct();
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    self.parser.check_struct(&def);
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    println!("Parsed a struct");
+                    unsafe {
+                        LLVMDumpValue(def.codegen(&mut self.parser));
+                    }
+                }
+                Some(Token::Global) | Some(Token::Const) => {
+                    let def = self.parser.parse_global();
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    self.parser.check_global(&def);
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    println!("Parsed a {}", if def.constant { "constant" } else { "global" });
+                    unsafe {
+                        LLVMDumpValue(def.codegen(&mut self.parser));
+                    }
+                }
                 Some(Token::Symbol(';')) => continue,
                 _ => {
-                    let exp = self.parser.parse_expression();
+                    let mut exp = self.parser.parse_expression();
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    let ty = self.parser.check_expression(&exp);
+                    if self.report_diagnostics() {
+                        continue;
+                    }
+                    let ty = ty.unwrap();
+                    self.generate_closures(&mut exp);
                     unsafe {
                         let anonymous_function = Function {
-                            proto: Box::new(Prototype { name: "".to_string(), args: vec![] }),
+                            proto: Box::new(Prototype::anonymous(ty.clone())),
                             body: exp,
                         };
+                        let mut function = anonymous_function.codegen(&mut self.parser);
+                        if let Type::Tuple(_) = ty {
+                            function = self.box_tuple(function);
+                        }
                         let mut args: Vec<LLVMGenericValueRef> = Vec::new();
-                        let ret = LLVMRunFunction(self.execution_engine,
-                                                  anonymous_function.codegen(&mut self.parser),
-                                                  0, args.as_mut_ptr());
-                        let double_ret = LLVMGenericValueToFloat(self.parser.get_double_type(), ret);
-                        println!("Returned {}", double_ret);
+                        let ret = LLVMRunFunction(self.execution_engine, function, 0, args.as_mut_ptr());
+
+                        // Errors raised by the runtime, e.g. an index out of range.
+                        if let Some(error) = runtime::take_error() {
+                            println!("Error: {}", error);
+                            continue;
+                        }
+
+                        match ty {
+                            Type::Int => println!("Returned {}", LLVMGenericValueToInt(ret, 1) as i64),
+                            Type::Bool => println!("Returned {}", LLVMGenericValueToInt(ret, 0) != 0),
+                            Type::Str | Type::Array | Type::Struct(_) => {
+                                let pointer = LLVMGenericValueToPointer(ret);
+                                println!("Returned {}", self.format_value(&ty, &pointer as *const _ as *const u8));
+                            }
+                            Type::Tuple(_) => {
+                                let pointer = LLVMGenericValueToPointer(ret);
+                                println!("Returned {}", self.format_value(&ty, pointer as *const u8));
+                            }
+                            Type::Function(..) => println!("Returned a function of type {}", ty),
+                            _ => println!("Returned {}", LLVMGenericValueToFloat(self.parser.get_double_type(), ret)),
+                        }
                     };
                 }
             }
         }
     }
+
+    // MCJIT can only run functions returning a scalar or a pointer, so the
+    // tuple a top-level expression gives is copied to the heap by a wrapper,
+    // which is run instead. Like structs, it is never freed.
+    unsafe fn box_tuple(&mut self, function: LLVMValueRef) -> LLVMValueRef {
+        let tuple_type = LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(function)));
+        let function_type = LLVMFunctionType(LLVMPointerType(tuple_type, 0), null_mut(), 0, 0);
+        let wrapper = LLVMAddFunction(self.parser.module(), CString::new("").unwrap().into_raw(), function_type);
+        let basic_block = LLVMAppendBasicBlockInContext(self.parser.context(), wrapper, CString::new("entry").unwrap().into_raw());
+
+        let builder = self.parser.builder();
+        LLVMPositionBuilderAtEnd(builder, basic_block);
+        let tuple = LLVMBuildCall(builder, function, null_mut(), 0, CString::new("calltmp").unwrap().into_raw());
+        let pointer = LLVMBuildMalloc(builder, tuple_type, CString::new("tuple").unwrap().into_raw());
+        LLVMBuildStore(builder, tuple, pointer);
+        LLVMBuildRet(builder, pointer);
+        wrapper
+    }
+
+    // Format a value of the given type, stored at the given address.
+    unsafe fn format_value(&self, ty: &Type, address: *const u8) -> String {
+        match ty {
+            Type::Int => (*(address as *const i64)).to_string(),
+            Type::Double => (*(address as *const f64)).to_string(),
+            Type::Bool => (*address != 0).to_string(),
+            Type::Str => {
+                let s = CStr::from_ptr(*(address as *const *const c_char));
+                format!("{:?}", s.to_string_lossy())
+            }
+            Type::Array => runtime::array_to_string(*(address as *const *const runtime::Array)),
+            Type::Struct(name) => {
+                // Structs only have double fields, laid out in order.
+                let values = *(address as *const *const f64);
+                let fields: Vec<String> = self.parser.get_struct_fields(name).unwrap().iter().enumerate()
+                    .map(|(i, field)| format!("{}: {}", field, *values.add(i)))
+                    .collect();
+                format!("{} {{ {} }}", name, fields.join(", "))
+            }
+            Type::Tuple(types) => {
+                // The elements are laid out as the target lays out the struct.
+                let target_data = LLVMGetExecutionEngineTargetData(self.execution_engine);
+                let tuple_type = self.parser.get_type(ty);
+                let elements: Vec<String> = types.iter().enumerate()
+                    .map(|(i, ty)| {
+                        let offset = LLVMOffsetOfElement(target_data, tuple_type, i as c_uint);
+                        self.format_value(ty, address.add(offset as usize))
+                    })
+                    .collect();
+                format!("({})", elements.join(", "))
+            }
+            Type::Function(..) => format!("a function of type {}", ty),
+            Type::Var(_) => panic!("unresolved type {}", ty),
+        }
+    }
+
+    // Generate the functions lifted out of the lambdas and nested definitions
+    // of the last item.
+    fn generate_closures(&mut self, expr: &mut Expr) {
+        for function in self.parser.convert_closures(expr) {
+            unsafe {
+                LLVMDumpValue(function.codegen(&mut self.parser));
+            }
+        }
+    }
+
+    // Print the errors found while parsing the last top-level item, and
+    // return whether there was any.
+    fn report_diagnostics(&mut self) -> bool {
+        let diagnostics = self.parser.take_diagnostics();
+        for diagnostic in diagnostics.iter() {
+            println!("Error: {}", diagnostic);
+        }
+        !diagnostics.is_empty()
+    }
 }
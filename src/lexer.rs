@@ -7,16 +7,43 @@ pub enum Token {
     // keywords
     Def,
     Extern,
+    If,
+    Then,
+    Else,
+    For,
+    In,
+    Binary,
+    Unary,
+    Var,
+    While,
+    Do,
+    Break,
+    Continue,
+    Let,
+    True,
+    False,
+    Struct,
+    Global,
+    Const,
     // primary
     Identifier(String),
+    Integer(i64),
     Number(f64),
+    String(String),
+    // brackets of array literals and indexing
+    LeftBracket,
+    RightBracket,
     // symbol
     Symbol(char),
+    // multi-character operator
+    Operator(String),
 }
 
 pub struct Lexer<'b> {
     buf: &'b str,
     pos: usize,
+    // Errors in the tokens read so far, reported by the parser
+    errors: Vec<String>,
 }
 
 impl<'b> Lexer<'b> {
@@ -25,9 +52,15 @@ impl<'b> Lexer<'b> {
         Lexer {
             buf: buf,
             pos: 0,
+            errors: Vec::new(),
         }
     }
 
+    #[inline]
+    pub fn take_errors(&mut self) -> Vec<String> {
+        self.errors.drain(..).collect()
+    }
+
     fn peek(&mut self) -> Option<char> {
         if self.pos < self.buf.len() {
             let b = unsafe { *self.buf.as_bytes().get_unchecked(self.pos) };
@@ -37,6 +70,10 @@ impl<'b> Lexer<'b> {
         }
     }
 
+    fn peek_next(&mut self) -> Option<char> {
+        self.buf.as_bytes().get(self.pos + 1).map(|b| *b as char)
+    }
+
     fn skip_whitespace(&mut self) {
         while self.pos < self.buf.len() {
             let b = unsafe { *self.buf.as_bytes().get_unchecked(self.pos) };
@@ -72,6 +109,48 @@ impl<'b> Lexer<'b> {
         }
     }
 
+    // Read a string literal up to its closing quote, the opening one being
+    // already skipped, and replace the escape sequences. Strings are
+    // NUL-terminated, so they cannot contain a NUL character. An unterminated
+    // string ends with the input, and an unknown escape sequence is kept as
+    // the escaped character.
+    fn string(&mut self) -> String {
+        let mut bytes = Vec::new();
+        loop {
+            let b = match self.buf.as_bytes().get(self.pos) {
+                Some(b) => *b,
+                None => {
+                    self.errors.push("unterminated string literal".to_string());
+                    break;
+                }
+            };
+            self.pos += 1;
+
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.peek() {
+                        Some('n') => b'\n',
+                        Some('t') => b'\t',
+                        Some('r') => b'\r',
+                        Some('\\') => b'\\',
+                        Some('"') => b'"',
+                        Some(c) => {
+                            self.errors.push(format!("unknown escape sequence in string literal: \\{}", c));
+                            c as u8
+                        }
+                        None => continue
+                    };
+                    self.pos += 1;
+                    bytes.push(escaped);
+                }
+                b'\0' => self.errors.push("NUL character in string literal".to_string()),
+                _ => bytes.push(b),
+            }
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
     fn identifier(&mut self) -> &'b str {
         let start = self.pos;
         while self.pos < self.buf.len() {
@@ -111,26 +190,77 @@ impl<'b> Iterator for Lexer<'b> {
                     Some(Token::Identifier(i.to_string()))
                 }
             }
-            // number
-            Some(c) if c.is_ascii_digit() || c == '.' => {
-                let n = self.number().parse::<f64>().unwrap();
-                Some(Token::Number(n))
+            // string
+            Some('"') => {
+                self.pos += 1;
+                Some(Token::String(self.string()))
+            }
+            // number, a '.' not followed by a digit is a field access
+            Some(c) if c.is_ascii_digit() || (c == '.' && self.peek_next().map_or(false, |n| n.is_ascii_digit())) => {
+                // Numbers without a decimal point are integers.
+                let n = self.number();
+                if n.contains('.') {
+                    return Some(Token::Number(n.parse::<f64>().unwrap()));
+                }
+                match n.parse::<i64>() {
+                    Ok(i) => Some(Token::Integer(i)),
+                    Err(_) => {
+                        self.errors.push(format!("integer literal out of range: {}", n));
+                        Some(Token::Integer(0))
+                    }
+                }
+            }
+            // bracket
+            Some('[') => {
+                self.pos += 1;
+                Some(Token::LeftBracket)
+            }
+            Some(']') => {
+                self.pos += 1;
+                Some(Token::RightBracket)
             }
-            // symbol
+            // symbol or operator
             _ => {
                 let s = unsafe { *self.buf.as_bytes().get_unchecked(self.pos) as char };
                 self.pos += 1;
+                if let Some(next) = self.peek() {
+                    let op: String = [s, next].iter().collect();
+                    if OPERATORS.contains(&op.as_str()) {
+                        self.pos += 1;
+                        return Some(Token::Operator(op));
+                    }
+                }
                 Some(Token::Symbol(s))
             }
         }
     }
 }
 
+const OPERATORS: [&str; 7] = ["<=", ">=", "==", "!=", "&&", "||", "->"];
+
 lazy_static! {
     static ref KEYWORDS: HashMap<&'static str, Token> = {
         let mut m = HashMap::new();
         m.insert("def", Token::Def);
         m.insert("extern", Token::Extern);
+        m.insert("if", Token::If);
+        m.insert("then", Token::Then);
+        m.insert("else", Token::Else);
+        m.insert("for", Token::For);
+        m.insert("in", Token::In);
+        m.insert("binary", Token::Binary);
+        m.insert("unary", Token::Unary);
+        m.insert("var", Token::Var);
+        m.insert("while", Token::While);
+        m.insert("do", Token::Do);
+        m.insert("break", Token::Break);
+        m.insert("continue", Token::Continue);
+        m.insert("let", Token::Let);
+        m.insert("true", Token::True);
+        m.insert("false", Token::False);
+        m.insert("struct", Token::Struct);
+        m.insert("global", Token::Global);
+        m.insert("const", Token::Const);
         m
     };
 }
@@ -158,30 +288,143 @@ fib(40)
         assert_eq!(lexer.next().unwrap(), Token::Symbol('('));
         assert_eq!(lexer.next().unwrap(), Token::Identifier("x".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol(')'));
-        assert_eq!(lexer.next().unwrap(), Token::Identifier("if".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::If);
         assert_eq!(lexer.next().unwrap(), Token::Identifier("x".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('<'));
-        assert_eq!(lexer.next().unwrap(), Token::Number(3.0));
-        assert_eq!(lexer.next().unwrap(), Token::Identifier("then".to_string()));
-        assert_eq!(lexer.next().unwrap(), Token::Number(1.0));
-        assert_eq!(lexer.next().unwrap(), Token::Identifier("else".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Integer(3));
+        assert_eq!(lexer.next().unwrap(), Token::Then);
+        assert_eq!(lexer.next().unwrap(), Token::Integer(1));
+        assert_eq!(lexer.next().unwrap(), Token::Else);
         assert_eq!(lexer.next().unwrap(), Token::Identifier("fib".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('('));
         assert_eq!(lexer.next().unwrap(), Token::Identifier("x".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('-'));
-        assert_eq!(lexer.next().unwrap(), Token::Number(1.0));
+        assert_eq!(lexer.next().unwrap(), Token::Integer(1));
         assert_eq!(lexer.next().unwrap(), Token::Symbol(')'));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('+'));
         assert_eq!(lexer.next().unwrap(), Token::Identifier("fib".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('('));
         assert_eq!(lexer.next().unwrap(), Token::Identifier("x".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('-'));
-        assert_eq!(lexer.next().unwrap(), Token::Number(2.0));
+        assert_eq!(lexer.next().unwrap(), Token::Integer(2));
         assert_eq!(lexer.next().unwrap(), Token::Symbol(')'));
         assert_eq!(lexer.next().unwrap(), Token::Identifier("fib".to_string()));
         assert_eq!(lexer.next().unwrap(), Token::Symbol('('));
-        assert_eq!(lexer.next().unwrap(), Token::Number(40.0));
+        assert_eq!(lexer.next().unwrap(), Token::Integer(40));
         assert_eq!(lexer.next().unwrap(), Token::Symbol(')'));
         assert!(lexer.next().is_none());
     }
+
+    #[test]
+    fn test_operators() {
+        let mut lexer = Lexer::new("a<=b >= c==d!=e<f =g&&h||i|j->k-l");
+
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("a".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator("<=".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("b".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator(">=".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("c".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator("==".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("d".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator("!=".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("e".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('<'));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("f".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('='));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("g".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator("&&".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("h".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator("||".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("i".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('|'));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("j".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Operator("->".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("k".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('-'));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("l".to_string()));
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn test_numbers() {
+        let mut lexer = Lexer::new("42 4.2 .5 3. 99999999999999999999 99999999999999999999.0");
+
+        assert_eq!(lexer.next().unwrap(), Token::Integer(42));
+        assert_eq!(lexer.next().unwrap(), Token::Number(4.2));
+        assert_eq!(lexer.next().unwrap(), Token::Number(0.5));
+        assert_eq!(lexer.next().unwrap(), Token::Number(3.0));
+        assert_eq!(lexer.next().unwrap(), Token::Integer(0));
+        assert_eq!(lexer.take_errors(), vec!["integer literal out of range: 99999999999999999999".to_string()]);
+        assert_eq!(lexer.next().unwrap(), Token::Number(1e20));
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut lexer = Lexer::new(r#"print("hello, world\n") "" "a\t\"b\"\\" "é#""#);
+
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("print".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('('));
+        assert_eq!(lexer.next().unwrap(), Token::String("hello, world\n".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol(')'));
+        assert_eq!(lexer.next().unwrap(), Token::String("".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::String("a\t\"b\"\\".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::String("é#".to_string()));
+        assert!(lexer.next().is_none());
+
+        let mut lexer = Lexer::new("\"a\0b\"");
+        assert_eq!(lexer.next().unwrap(), Token::String("ab".to_string()));
+        assert_eq!(lexer.take_errors(), vec!["NUL character in string literal".to_string()]);
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut lexer = Lexer::new("struct P { x, y } p.x + .5");
+
+        assert_eq!(lexer.next().unwrap(), Token::Struct);
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("P".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('{'));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("x".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol(','));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("y".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('}'));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("p".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('.'));
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("x".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('+'));
+        assert_eq!(lexer.next().unwrap(), Token::Number(0.5));
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn test_brackets() {
+        let mut lexer = Lexer::new("a[i] = [1, 2.5]");
+
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("a".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::LeftBracket);
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("i".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::RightBracket);
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('='));
+        assert_eq!(lexer.next().unwrap(), Token::LeftBracket);
+        assert_eq!(lexer.next().unwrap(), Token::Integer(1));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol(','));
+        assert_eq!(lexer.next().unwrap(), Token::Number(2.5));
+        assert_eq!(lexer.next().unwrap(), Token::RightBracket);
+        assert!(lexer.next().is_none());
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut lexer = Lexer::new("global count = 0 const RATE = 0.25");
+
+        assert_eq!(lexer.next().unwrap(), Token::Global);
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("count".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('='));
+        assert_eq!(lexer.next().unwrap(), Token::Integer(0));
+        assert_eq!(lexer.next().unwrap(), Token::Const);
+        assert_eq!(lexer.next().unwrap(), Token::Identifier("RATE".to_string()));
+        assert_eq!(lexer.next().unwrap(), Token::Symbol('='));
+        assert_eq!(lexer.next().unwrap(), Token::Number(0.25));
+        assert!(lexer.next().is_none());
+    }
 }
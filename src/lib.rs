@@ -5,4 +5,7 @@ extern crate llvm_sys as llvm;
 pub mod lexer;
 pub mod parser;
 pub mod ast;
+pub mod typeck;
+pub mod closure;
+pub mod runtime;
 pub mod jit;
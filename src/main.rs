@@ -4,8 +4,12 @@ use kaleidoscope::jit::JIT;
 
 fn main() {
     let mut jit = JIT::new(r"
-        def test(x) (1+2+x)*(x+(1+2));
-        test(3);
+        def fib(x)
+            if x < 3 then
+                1
+            else
+                fib(x-1)+fib(x-2);
+        fib(10);
     ");
 
     jit.run();
@@ -1,14 +1,28 @@
 use std::collections::HashMap;
 use std::ffi::{CString, CStr};
 use std::os::raw::c_uint;
+use std::ptr::null_mut;
 
 use llvm::prelude::*;
 use llvm::core::*;
 use llvm::target::*;
 use llvm::transforms::scalar::*;
+use llvm::transforms::util::*;
 
 use crate::lexer::{Lexer, Token};
-use crate::ast::{AST, Expr, NumberExpr, VariableExpr, BinaryExpr, CallExpr, Prototype, Function};
+use crate::typeck::TypeChecker;
+use crate::closure;
+use crate::runtime;
+use crate::ast::{AST, Type, Expr, IntegerExpr, NumberExpr, BooleanExpr, StringExpr, ArrayExpr, TupleExpr, IndexExpr, FieldExpr, VariableExpr, UnaryExpr, BinaryExpr, CallExpr, LambdaExpr, DefExpr, IfExpr, ForExpr, VarExpr, LetExpr, WhileExpr, BreakExpr, ContinueExpr, BlockExpr, Prototype, Function, StructDef, GlobalDef, is_builtin_binary};
+
+// What a name is bound to during codegen.
+#[derive(Clone, Copy, Debug)]
+pub enum NamedValue {
+    // A mutable variable, living in a stack slot
+    Variable(LLVMValueRef),
+    // An immutable SSA value
+    Value(LLVMValueRef),
+}
 
 pub struct Parser<'b> {
     lexer: Lexer<'b>,
@@ -18,7 +32,19 @@ pub struct Parser<'b> {
     context: LLVMContextRef,
     builder: LLVMBuilderRef,
     module: LLVMModuleRef,
-    name_values: HashMap<String, LLVMValueRef>,
+    name_values: HashMap<String, NamedValue>,
+    // Global variables and constants, visible from every function
+    globals: HashMap<String, NamedValue>,
+    binop_precedence: HashMap<String, i32>,
+    // Field names of the structs, by struct name
+    structs: HashMap<String, Vec<String>>,
+    diagnostics: Vec<String>,
+    type_checker: TypeChecker,
+    loop_depth: usize,
+    // Number of lambdas and nested definitions parsed, the last one's id
+    nested: usize,
+    bindings: Vec<(String, bool)>,
+    loops: Vec<(LLVMBasicBlockRef, LLVMBasicBlockRef)>,
     function_pass_manager: LLVMPassManagerRef,
 }
 
@@ -50,6 +76,7 @@ impl<'b> Parser<'b> {
         };
         unsafe {
             // optimization passes
+            LLVMAddPromoteMemoryToRegisterPass(function_pass_manager);
             LLVMAddBasicAliasAnalysisPass(function_pass_manager);
             LLVMAddInstructionCombiningPass(function_pass_manager);
             LLVMAddReassociatePass(function_pass_manager);
@@ -59,7 +86,7 @@ impl<'b> Parser<'b> {
             LLVMInitializeFunctionPassManager(function_pass_manager);
         }
 
-        Parser {
+        let mut parser = Parser {
             lexer: Lexer::new(buf),
             token: None,
             ast: Vec::new(),
@@ -68,7 +95,28 @@ impl<'b> Parser<'b> {
             builder: builder,
             module: module,
             name_values: HashMap::new(),
+            globals: HashMap::new(),
+            binop_precedence: BINOP_PRECEDENCE.clone(),
+            structs: HashMap::new(),
+            diagnostics: Vec::new(),
+            type_checker: TypeChecker::new(),
+            loop_depth: 0,
+            nested: 0,
+            bindings: Vec::new(),
+            loops: Vec::new(),
             function_pass_manager: function_pass_manager,
+        };
+        parser.declare_runtime();
+        parser
+    }
+
+    // Declare the runtime functions, to both the type checker and the module.
+    fn declare_runtime(&mut self) {
+        for mut proto in runtime::prototypes() {
+            self.type_checker.declare(&mut proto);
+            unsafe {
+                proto.codegen(self);
+            }
         }
     }
 
@@ -87,30 +135,234 @@ impl<'b> Parser<'b> {
     #[inline]
     pub fn function_pass_manager(&self) -> LLVMPassManagerRef { self.function_pass_manager }
 
+    // Record an error in the source. Parsing goes on, but the enclosing
+    // top-level item is not generated.
+    #[inline]
+    pub fn error(&mut self, message: String) {
+        self.diagnostics.push(message)
+    }
+
+    #[inline]
+    pub fn take_diagnostics(&mut self) -> Vec<String> {
+        self.diagnostics.drain(..).collect()
+    }
+
+    // Type check a definition before codegen, the error if any becomes
+    // a diagnostic.
+    pub fn check_function(&mut self, function: &mut Function) {
+        if let Err(message) = self.type_checker.check_function(function) {
+            self.error(message);
+        }
+    }
+
+    #[inline]
+    pub fn check_extern(&mut self, proto: &mut Prototype) {
+        self.type_checker.declare(proto)
+    }
+
+    #[inline]
+    pub fn check_struct(&mut self, def: &StructDef) {
+        self.type_checker.declare_struct(def)
+    }
+
+    // Type check a global before codegen, and compute its value, which must
+    // be known at compile time.
+    pub fn check_global(&mut self, def: &GlobalDef) {
+        let result = self.type_checker.check_global(def).and_then(|ty| {
+            match def.evaluate(self) {
+                Ok(_) => Ok(ty),
+                Err(message) => Err(format!("invalid value for <{}>: {}", def.name, message)),
+            }
+        });
+        match result {
+            Ok(ty) => self.type_checker.declare_global(def, ty),
+            Err(message) => self.error(message),
+        }
+    }
+
+    #[inline]
+    pub fn get_struct_fields(&self, name: &str) -> Option<&Vec<String>> {
+        self.structs.get(name)
+    }
+
+    // Type check a top-level expression, returning its type.
+    pub fn check_expression(&mut self, expr: &Expr) -> Option<Type> {
+        match self.type_checker.check_expression(expr) {
+            Ok(ty) => Some(ty),
+            Err(message) => {
+                self.error(message);
+                None
+            }
+        }
+    }
+
+    // Lift the lambdas and nested definitions of a checked definition or
+    // top-level expression into functions of their own, to be generated
+    // before it. They are declared right away, as they may refer to each other.
+    pub fn convert_closures(&mut self, expr: &mut Expr) -> Vec<Function> {
+        let functions = closure::convert(expr, &mut self.type_checker);
+        for function in functions.iter() {
+            unsafe {
+                function.proto.codegen(self);
+            }
+        }
+        functions
+    }
+
     #[inline]
-    pub fn get_named_value(&self, name: String) -> Option<&LLVMValueRef> {
-        self.name_values.get(&name)
+    pub fn get_named_value(&self, name: String) -> Option<&NamedValue> {
+        self.name_values.get(&name).or_else(|| self.globals.get(&name))
     }
 
     #[inline]
-    pub fn insert_named_value(&mut self, name: String, value: LLVMValueRef) -> Option<LLVMValueRef> {
+    pub fn insert_named_value(&mut self, name: String, value: NamedValue) -> Option<NamedValue> {
         self.name_values.insert(name, value)
     }
 
+    #[inline]
+    pub fn insert_global(&mut self, name: String, value: NamedValue) -> Option<NamedValue> {
+        self.globals.insert(name, value)
+    }
+
+    #[inline]
+    pub fn remove_named_value(&mut self, name: String) -> Option<NamedValue> {
+        self.name_values.remove(&name)
+    }
+
     #[inline]
     pub fn clear_named_value(&mut self) {
         self.name_values.clear()
     }
 
+    // Bindings visible to the expression being parsed, innermost last,
+    // with whether they can be assigned to.
+    #[inline]
+    fn push_binding(&mut self, name: String, mutable: bool) {
+        self.bindings.push((name, mutable))
+    }
+
+    #[inline]
+    fn pop_bindings(&mut self, count: usize) {
+        let len = self.bindings.len() - count;
+        self.bindings.truncate(len);
+    }
+
+    #[inline]
+    fn is_immutable(&self, name: &str) -> bool {
+        match self.bindings.iter().rev().find(|(n, _)| n == name) {
+            Some((_, mutable)) => !mutable,
+            None => false
+        }
+    }
+
+    // Enter a loop, whose 'continue' and 'break' targets are given.
+    #[inline]
+    pub fn push_loop(&mut self, continue_block: LLVMBasicBlockRef, break_block: LLVMBasicBlockRef) {
+        self.loops.push((continue_block, break_block))
+    }
+
+    #[inline]
+    pub fn pop_loop(&mut self) {
+        self.loops.pop();
+    }
+
+    #[inline]
+    pub fn current_loop(&self) -> Option<(LLVMBasicBlockRef, LLVMBasicBlockRef)> {
+        self.loops.last().cloned()
+    }
+
     #[inline]
     pub fn get_double_type(&self) -> LLVMTypeRef {
         unsafe { LLVMDoubleTypeInContext(self.context) }
     }
 
     #[inline]
-    pub fn get_function_type(&self, argc: usize) -> LLVMTypeRef {
-        let mut arg_types = vec![self.get_double_type(); argc];
-        unsafe { LLVMFunctionType(self.get_double_type(), arg_types.as_mut_ptr(), argc as c_uint, 0) }
+    pub fn get_int_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMInt64TypeInContext(self.context) }
+    }
+
+    #[inline]
+    pub fn get_bool_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMInt1TypeInContext(self.context) }
+    }
+
+    #[inline]
+    pub fn get_string_type(&self) -> LLVMTypeRef {
+        unsafe { LLVMPointerType(LLVMInt8TypeInContext(self.context), 0) }
+    }
+
+    // An array is a pointer to its length and its elements, all doubles.
+    #[inline]
+    pub fn get_array_type(&self) -> LLVMTypeRef {
+        unsafe {
+            let mut field_types = vec![self.get_int_type(), LLVMPointerType(self.get_double_type(), 0)];
+            LLVMPointerType(LLVMStructTypeInContext(self.context, field_types.as_mut_ptr(), 2, 0), 0)
+        }
+    }
+
+    #[inline]
+    pub fn get_type(&self, ty: &Type) -> LLVMTypeRef {
+        match ty {
+            Type::Int => self.get_int_type(),
+            Type::Double => self.get_double_type(),
+            Type::Bool => self.get_bool_type(),
+            Type::Str => self.get_string_type(),
+            Type::Array => self.get_array_type(),
+            Type::Struct(name) => unsafe {
+                LLVMPointerType(LLVMGetTypeByName(self.module, CString::new(name.clone()).unwrap().into_raw()), 0)
+            },
+            Type::Function(arg_types, return_type) => self.get_closure_type(arg_types, return_type),
+            Type::Tuple(types) => unsafe {
+                let mut element_types: Vec<LLVMTypeRef> = types.iter().map(|ty| self.get_type(ty)).collect();
+                LLVMStructTypeInContext(self.context, element_types.as_mut_ptr(), element_types.len() as c_uint, 0)
+            },
+            Type::Var(_) => panic!("unresolved type {}", ty),
+        }
+    }
+
+    #[inline]
+    pub fn get_function_type(&self, arg_types: &[Type], return_type: &Type) -> LLVMTypeRef {
+        let mut arg_types: Vec<LLVMTypeRef> = arg_types.iter().map(|ty| self.get_type(ty)).collect();
+        unsafe { LLVMFunctionType(self.get_type(return_type), arg_types.as_mut_ptr(), arg_types.len() as c_uint, 0) }
+    }
+
+    // The function behind a closure takes its environment, as an i8*, before
+    // the other arguments.
+    #[inline]
+    pub fn get_closure_function_type(&self, arg_types: &[Type], return_type: &Type) -> LLVMTypeRef {
+        let mut arg_types: Vec<LLVMTypeRef> = arg_types.iter().map(|ty| self.get_type(ty)).collect();
+        arg_types.insert(0, self.get_string_type());
+        unsafe { LLVMFunctionType(self.get_type(return_type), arg_types.as_mut_ptr(), arg_types.len() as c_uint, 0) }
+    }
+
+    // A function value is a pointer to a closure: the function and its
+    // environment.
+    #[inline]
+    pub fn get_closure_type(&self, arg_types: &[Type], return_type: &Type) -> LLVMTypeRef {
+        unsafe {
+            let function_type = self.get_closure_function_type(arg_types, return_type);
+            let mut field_types = vec![LLVMPointerType(function_type, 0), self.get_string_type()];
+            LLVMPointerType(LLVMStructTypeInContext(self.context, field_types.as_mut_ptr(), 2, 0), 0)
+        }
+    }
+
+    // Create an alloca in the entry block of the function, where mem2reg
+    // can promote it to a register.
+    pub fn create_entry_block_alloca(&self, function: LLVMValueRef, name: &str, ty: LLVMTypeRef) -> LLVMValueRef {
+        unsafe {
+            let builder = LLVMCreateBuilderInContext(self.context);
+            let entry_block = LLVMGetEntryBasicBlock(function);
+            let first_instruction = LLVMGetFirstInstruction(entry_block);
+            if first_instruction == null_mut() {
+                LLVMPositionBuilderAtEnd(builder, entry_block);
+            } else {
+                LLVMPositionBuilderBefore(builder, first_instruction);
+            }
+
+            let alloca = LLVMBuildAlloca(builder, ty, CString::new(name).unwrap().into_raw());
+            LLVMDisposeBuilder(builder);
+            alloca
+        }
     }
 
     #[inline]
@@ -124,30 +376,81 @@ impl<'b> Parser<'b> {
     #[inline]
     pub fn get_next_token(&mut self) {
         self.token = self.lexer.next();
+        self.diagnostics.extend(self.lexer.take_errors());
     }
 
-    // top ::= definition | extern | expression | ';'
+    // top ::= definition | extern | struct | global | expression | ';'
     pub fn parse(&mut self) {
         loop {
+            let errors = self.diagnostics.len();
             self.get_next_token();
 
             match self.token {
                 None => break,
                 Some(Token::Def) => {
-                    let def = self.parse_definition();
+                    let mut def = self.parse_definition();
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    self.check_function(&mut def);
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    self.generate_closures(&mut def.body);
                     let codegen = self.get_codegen_string(&def);
                     self.ast.push(def);
                     self.codegen.push(codegen);
                 }
                 Some(Token::Extern) => {
-                    let ext = self.parse_extern();
+                    let mut ext = self.parse_extern();
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    self.check_extern(&mut ext);
                     let codegen = self.get_codegen_string(&ext);
                     self.ast.push(ext);
                     self.codegen.push(codegen);
                 }
+                Some(Token::Struct) => {
+                    let def = self.parse_struct();
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    self.check_struct(&def);
+                    let codegen = self.get_codegen_string(&def);
+                    self.ast.push(def);
+                    self.codegen.push(codegen);
+                }
+                Some(Token::Global) | Some(Token::Const) => {
+                    let def = self.parse_global();
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    self.check_global(&def);
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    let codegen = self.get_codegen_string(&def);
+                    self.ast.push(def);
+                    self.codegen.push(codegen);
+                }
                 Some(Token::Symbol(';')) => continue,
                 _ => {
-                    let exp = self.parse_expression();
+                    let mut exp = self.parse_expression();
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    let ty = self.check_expression(&exp);
+                    if self.diagnostics.len() > errors {
+                        continue;
+                    }
+                    self.generate_closures(&mut exp);
+
+                    // Top-level expressions are wrapped into anonymous functions.
+                    let exp = Box::new(Function {
+                        proto: Box::new(Prototype::anonymous(ty.unwrap())),
+                        body: exp,
+                    });
                     let codegen = self.get_codegen_string(&exp);
                     self.ast.push(exp);
                     self.codegen.push(codegen);
@@ -156,34 +459,110 @@ impl<'b> Parser<'b> {
         }
     }
 
+    fn generate_closures(&mut self, expr: &mut Expr) {
+        for function in self.convert_closures(expr) {
+            let function = Box::new(function);
+            let codegen = self.get_codegen_string(&function);
+            self.ast.push(function);
+            self.codegen.push(codegen);
+        }
+    }
+
     // definition ::= 'def' prototype expression
     pub fn parse_definition(&mut self) -> Box<Function> {
         assert_eq!(self.token, Some(Token::Def));
         self.get_next_token();
 
+        let proto = self.parse_prototype();
+        self.check_function_name(&proto.name);
+
+        for arg in proto.args.iter() {
+            self.push_binding(arg.clone(), true);
+        }
+        let body = self.parse_expression();
+        self.pop_bindings(proto.args.len());
+
         Box::new(Function {
-            proto: self.parse_prototype(),
-            body: self.parse_expression(),
+            proto: proto,
+            body: body,
         })
     }
 
-    // prototype ::= id '(' id* ')'
+    // prototype ::= id '(' arg* ')' annotation
+    //           ::= 'binary' op number? '(' arg arg ')' annotation
+    //           ::= 'unary' op '(' arg ')' annotation
+    // arg ::= id annotation ','?
     fn parse_prototype(&mut self) -> Box<Prototype> {
-        let name = match self.token.clone() {
-            Some(Token::Identifier(id)) => id,
+        let (name, operands, precedence) = match self.token.clone() {
+            Some(Token::Identifier(id)) => {
+                self.get_next_token();
+                (id, None, None)
+            }
+            Some(Token::Binary) => {
+                self.get_next_token();
+
+                let op = match self.token.clone() {
+                    Some(Token::Symbol(op)) if is_operator_char(op) => op.to_string(),
+                    Some(Token::Operator(op)) => op,
+                    _ => panic!("unexpected token: expected operator, got {:?}", self.token)
+                };
+                self.get_next_token();
+
+                // The precedence is optional.
+                let precedence = match self.token {
+                    Some(Token::Integer(n)) => {
+                        if n < 1 || n > 100 {
+                            panic!("invalid precedence <{}>: must be 1..100", n);
+                        }
+                        self.get_next_token();
+                        n as i32
+                    }
+                    _ => 30
+                };
+
+                // Builtin operators are generated as instructions, a definition
+                // would never be called.
+                if is_builtin_binary(&op) {
+                    self.error(format!("cannot redefine builtin operator <{}>", op));
+                    (format!("binary{}", op), Some(2), None)
+                } else {
+                    (format!("binary{}", op), Some(2), Some((op, precedence)))
+                }
+            }
+            Some(Token::Unary) => {
+                self.get_next_token();
+
+                let op = match self.token {
+                    Some(Token::Symbol(op)) if is_operator_char(op) => op,
+                    _ => panic!("unexpected token: expected operator, got {:?}", self.token)
+                };
+                self.get_next_token();
+
+                if op == '-' || op == '!' {
+                    self.error(format!("cannot redefine builtin operator <{}>", op));
+                }
+
+                (format!("unary{}", op), Some(1), None)
+            }
             _ => panic!("unexpected token: expected Identifier, got {:?}", self.token)
         };
-        self.get_next_token();
 
         assert_eq!(self.token, Some(Token::Symbol('(')));
         self.get_next_token();
 
         let mut args = Vec::new();
+        let mut arg_types = Vec::new();
         loop {
             match self.token.clone() {
                 Some(Token::Identifier(id)) => {
-                    args.push(id);
                     self.get_next_token();
+                    args.push(id);
+                    arg_types.push(self.parse_type_annotation());
+
+                    // Arguments may be separated by commas.
+                    if self.token == Some(Token::Symbol(',')) {
+                        self.get_next_token();
+                    }
                 }
                 Some(Token::Symbol(')')) => {
                     self.get_next_token();
@@ -192,34 +571,235 @@ impl<'b> Parser<'b> {
                 _ => panic!("unexpected token: expected ')', got {:?}", self.token)
             }
         }
+
+        let return_type = self.parse_type_annotation();
+
+        if let Some(operands) = operands {
+            if args.len() != operands {
+                panic!("invalid number of operands for operator <{}>: expected {}, got {}", name, operands, args.len());
+            }
+        }
+
+        // Register a binary operator right away, so that it can be used by
+        // anything parsed after this prototype.
+        if let Some((op, precedence)) = precedence {
+            self.binop_precedence.insert(op, precedence);
+        }
+
         Box::new(Prototype {
             name: name,
             args: args,
+            arg_types: arg_types,
+            return_type: return_type,
+            env: None,
         })
     }
 
+    // annotation ::= (':' type)?
+    // Without an annotation, the type is left to the type checker to infer.
+    fn parse_type_annotation(&mut self) -> Type {
+        if self.token != Some(Token::Symbol(':')) {
+            return self.type_checker.new_variable();
+        }
+        self.get_next_token();
+
+        self.parse_type()
+    }
+
+    // type ::= 'int' | 'double' | 'bool' | 'string' | 'array' | id
+    //        | '(' (type (',' type)*)? ')' '->' type
+    //        | '(' type (',' type)+ ')'
+    fn parse_type(&mut self) -> Type {
+        if self.token == Some(Token::Symbol('(')) {
+            self.get_next_token();
+
+            let mut arg_types = Vec::new();
+            loop {
+                match self.token {
+                    Some(Token::Symbol(')')) => {
+                        self.get_next_token();
+                        break;
+                    }
+                    Some(Token::Symbol(',')) => {
+                        self.get_next_token();
+                    }
+                    _ => {
+                        arg_types.push(self.parse_type())
+                    }
+                }
+            }
+
+            // Without an arrow, it is a tuple type.
+            if self.token != Some(Token::Operator("->".to_string())) {
+                if arg_types.len() < 2 {
+                    panic!("unexpected token: expected '->', got {:?}", self.token);
+                }
+                return Type::Tuple(arg_types);
+            }
+            self.get_next_token();
+
+            return Type::Function(arg_types, Box::new(self.parse_type()));
+        }
+
+        let name = match self.token.clone() {
+            Some(Token::Identifier(id)) => id,
+            _ => panic!("unexpected token: expected type, got {:?}", self.token)
+        };
+        self.get_next_token();
+
+        match name.as_str() {
+            "int" => Type::Int,
+            "double" => Type::Double,
+            "bool" => Type::Bool,
+            "string" => Type::Str,
+            "array" => Type::Array,
+            _ if self.structs.contains_key(&name) => Type::Struct(name),
+            _ => {
+                self.error(format!("unknown type <{}>", name));
+                Type::Double
+            }
+        }
+    }
+
     // extern ::= 'extern' prototype
     pub fn parse_extern(&mut self) -> Box<Prototype> {
         assert_eq!(self.token, Some(Token::Extern));
         self.get_next_token();
 
-        self.parse_prototype()
+        let proto = self.parse_prototype();
+        self.check_function_name(&proto.name);
+        proto
+    }
+
+    // A struct's constructor takes its name, so functions and structs cannot
+    // share one.
+    fn check_function_name(&mut self, name: &str) {
+        if self.structs.contains_key(name) {
+            self.error(format!("redefinition of <{}>", name));
+        }
+    }
+
+    // struct ::= 'struct' id '{' (id (',' id)*)? '}'
+    pub fn parse_struct(&mut self) -> Box<StructDef> {
+        assert_eq!(self.token, Some(Token::Struct));
+        self.get_next_token();
+
+        let name = match self.token.clone() {
+            Some(Token::Identifier(id)) => id,
+            _ => panic!("unexpected token: expected struct name, got {:?}", self.token)
+        };
+        self.get_next_token();
+
+        if self.token != Some(Token::Symbol('{')) {
+            panic!("unexpected token: expected '{{', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let errors = self.diagnostics.len();
+        let mut fields: Vec<String> = Vec::new();
+        loop {
+            match self.token.clone() {
+                Some(Token::Symbol('}')) => {
+                    self.get_next_token();
+                    break;
+                }
+                Some(Token::Symbol(',')) => self.get_next_token(),
+                Some(Token::Identifier(id)) => {
+                    self.get_next_token();
+                    if fields.contains(&id) {
+                        self.error(format!("duplicate field <{}> in struct <{}>", id, name));
+                    }
+                    fields.push(id);
+                }
+                _ => panic!("unexpected token: expected [ id | ',' | '}}' ], got {:?}", self.token)
+            }
+        }
+
+        // Register the struct now, so that it can be used as a type annotation,
+        // unless it is invalid and will not be generated.
+        let function = unsafe {
+            LLVMGetNamedFunction(self.module, CString::new(name.clone()).unwrap().into_raw())
+        };
+        if self.structs.contains_key(&name) {
+            self.error(format!("redefinition of struct <{}>", name));
+        } else if function != null_mut() {
+            self.error(format!("redefinition of <{}>", name));
+        } else if self.diagnostics.len() == errors {
+            self.structs.insert(name.clone(), fields.clone());
+        }
+
+        Box::new(StructDef {
+            name: name,
+            fields: fields,
+        })
+    }
+
+    // global ::= 'global' id '=' expression
+    //        ::= 'const' id '=' expression
+    pub fn parse_global(&mut self) -> Box<GlobalDef> {
+        let constant = match self.token {
+            Some(Token::Global) => false,
+            Some(Token::Const) => true,
+            _ => panic!("unexpected token: expected [ 'global' | 'const' ], got {:?}", self.token)
+        };
+        self.get_next_token();
+
+        let name = match self.token.clone() {
+            Some(Token::Identifier(id)) => id,
+            _ => panic!("unexpected token: expected identifier, got {:?}", self.token)
+        };
+        self.get_next_token();
+
+        if self.token != Some(Token::Symbol('=')) {
+            panic!("unexpected token: expected '=', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let value = self.parse_expression();
+
+        Box::new(GlobalDef {
+            name: name,
+            value: value,
+            constant: constant,
+        })
     }
 
-    // expression ::= primary binoprhs
+    // expression ::= unary binoprhs
     pub fn parse_expression(&mut self) -> Box<Expr> {
-        let lhs = self.parse_primary();
+        let lhs = self.parse_unary();
         self.parse_binoprhs(lhs, 0)
     }
 
-    // primary ::= id ['(' expression* ')'] | number | '(' expression ')'
-    fn parse_primary(&mut self) -> Box<Expr> {
-        match self.token.clone() {
-            Some(Token::Identifier(id)) => {
-                let name = id;
+    // unary ::= postfix | op unary
+    fn parse_unary(&mut self) -> Box<Expr> {
+        match self.token {
+            Some(Token::Symbol(op)) if is_operator_char(op) => {
                 self.get_next_token();
+                let mut operand = self.parse_unary();
+
+                // As in maths, '^' binds tighter than the built-in '-', so that
+                // -2 ^ 2 is -(2 ^ 2).
+                if op == '-' {
+                    let precedence = self.binop_precedence["^"];
+                    operand = self.parse_binoprhs(operand, precedence);
+                }
 
-                if self.token == Some(Token::Symbol('(')) {
+                Box::new(Expr::UnaryExpr(UnaryExpr {
+                    op: op,
+                    operand: operand,
+                }))
+            }
+            _ => self.parse_postfix()
+        }
+    }
+
+    // postfix ::= primary ('(' expression* ')' | '[' expression ']' | '.' id)*
+    fn parse_postfix(&mut self) -> Box<Expr> {
+        let mut expr = self.parse_primary();
+
+        loop {
+            match self.token {
+                Some(Token::Symbol('(')) => {
                     self.get_next_token();
 
                     let mut args = Vec::new();
@@ -237,35 +817,496 @@ impl<'b> Parser<'b> {
                             }
                         }
                     }
-                    Box::new(Expr::CallExpr(CallExpr {
-                        callee: name,
+
+                    expr = Box::new(Expr::CallExpr(CallExpr {
+                        callee: expr,
                         args: args,
-                    }))
-                } else {
-                    Box::new(Expr::VariableExpr(VariableExpr { name: name }))
+                    }));
                 }
-            }
-            Some(Token::Number(n)) => {
-                self.get_next_token();
-                Box::new(Expr::NumberExpr(NumberExpr { val: n }))
-            }
-            Some(Token::Symbol('(')) => {
-                self.get_next_token();
-                let expr = self.parse_expression();
+                Some(Token::LeftBracket) => {
+                    self.get_next_token();
+                    let index = self.parse_expression();
 
-                if self.token == Some(Token::Symbol(')')) {
+                    if self.token != Some(Token::RightBracket) {
+                        panic!("unexpected token: expected ']', got {:?}", self.token)
+                    }
                     self.get_next_token();
-                    expr
-                } else {
-                    panic!("unexpected token: expected ')', got {:?}", self.token)
+
+                    expr = Box::new(Expr::IndexExpr(IndexExpr {
+                        array: expr,
+                        index: index,
+                    }));
+                }
+                Some(Token::Symbol('.')) => {
+                    self.get_next_token();
+                    let field = match self.token.clone() {
+                        Some(Token::Identifier(id)) => id,
+                        _ => panic!("unexpected token: expected field name, got {:?}", self.token)
+                    };
+                    self.get_next_token();
+
+                    expr = Box::new(Expr::FieldExpr(FieldExpr {
+                        object: expr,
+                        field: field,
+                    }));
                 }
+                _ => return expr
             }
-            _ => panic!("unexpected token: expected [ id | number | '(' ], got {:?}", self.token)
         }
     }
 
-    // binoprhs ::= ('+' primary)*
-    fn parse_binoprhs(&mut self, mut lhs: Box<Expr>, lhs_precedence: i32) -> Box<Expr> {
+    // primary ::= id | number | string | 'true' | 'false' | '(' expression ')' | tuple
+    //           | ifexpr | forexpr | varexpr | letexpr | whileexpr | 'break' | 'continue' | block | array | lambda
+    //           | defexpr
+    fn parse_primary(&mut self) -> Box<Expr> {
+        match self.token.clone() {
+            Some(Token::Def) => self.parse_nested_definition(),
+            Some(Token::If) => self.parse_if(),
+            Some(Token::For) => self.parse_for(),
+            Some(Token::Var) => self.parse_var(),
+            Some(Token::Let) => self.parse_let(),
+            Some(Token::While) => self.parse_while(),
+            Some(Token::Break) => {
+                self.get_next_token();
+                if self.loop_depth == 0 {
+                    self.error("'break' outside of a loop".to_string());
+                }
+                Box::new(Expr::BreakExpr(BreakExpr))
+            }
+            Some(Token::Continue) => {
+                self.get_next_token();
+                if self.loop_depth == 0 {
+                    self.error("'continue' outside of a loop".to_string());
+                }
+                Box::new(Expr::ContinueExpr(ContinueExpr))
+            }
+            Some(Token::Identifier(id)) => {
+                self.get_next_token();
+                Box::new(Expr::VariableExpr(VariableExpr { name: id }))
+            }
+            Some(Token::Integer(n)) => {
+                self.get_next_token();
+                Box::new(Expr::IntegerExpr(IntegerExpr { val: n }))
+            }
+            Some(Token::Number(n)) => {
+                self.get_next_token();
+                Box::new(Expr::NumberExpr(NumberExpr { val: n }))
+            }
+            Some(Token::String(s)) => {
+                self.get_next_token();
+                Box::new(Expr::StringExpr(StringExpr { val: s }))
+            }
+            Some(Token::True) | Some(Token::False) => {
+                let val = self.token == Some(Token::True);
+                self.get_next_token();
+                Box::new(Expr::BooleanExpr(BooleanExpr { val: val }))
+            }
+            Some(Token::Symbol('{')) => self.parse_block(),
+            Some(Token::LeftBracket) => self.parse_array(),
+            Some(Token::Symbol('\\')) => self.parse_lambda(),
+            Some(Token::Symbol('(')) => self.parse_paren(),
+            _ => panic!("unexpected token: expected [ id | number | string | true | false | '(' | if | for | var | let | while | break | continue | '{{' | '[' | '\\' | def ], got {:?}", self.token)
+        }
+    }
+
+    // A parenthesized expression, or a tuple if there is a comma after the
+    // first element.
+    // tuple ::= '(' expression (',' expression)+ ')'
+    fn parse_paren(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::Symbol('(')));
+        self.get_next_token();
+
+        let expr = self.parse_expression();
+        if self.token != Some(Token::Symbol(',')) {
+            if self.token != Some(Token::Symbol(')')) {
+                panic!("unexpected token: expected [ ',' | ')' ], got {:?}", self.token)
+            }
+            self.get_next_token();
+            return expr;
+        }
+
+        let mut elements = vec![expr];
+        while self.token == Some(Token::Symbol(',')) {
+            self.get_next_token();
+            elements.push(self.parse_expression());
+        }
+
+        if self.token != Some(Token::Symbol(')')) {
+            panic!("unexpected token: expected [ ',' | ')' ], got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        Box::new(Expr::TupleExpr(TupleExpr {
+            elements: elements,
+        }))
+    }
+
+    // ifexpr ::= 'if' expression 'then' expression 'else' expression
+    fn parse_if(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::If));
+        self.get_next_token();
+
+        let cond = self.parse_expression();
+
+        if self.token != Some(Token::Then) {
+            panic!("unexpected token: expected 'then', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let then_expr = self.parse_expression();
+
+        if self.token != Some(Token::Else) {
+            panic!("unexpected token: expected 'else', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let else_expr = self.parse_expression();
+
+        Box::new(Expr::IfExpr(IfExpr {
+            cond: cond,
+            then_expr: then_expr,
+            else_expr: else_expr,
+        }))
+    }
+
+    // forexpr ::= 'for' id '=' expression ',' expression (',' expression)? 'in' expression
+    fn parse_for(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::For));
+        self.get_next_token();
+
+        let var_name = match self.token.clone() {
+            Some(Token::Identifier(id)) => id,
+            _ => panic!("unexpected token: expected Identifier, got {:?}", self.token)
+        };
+        self.get_next_token();
+
+        if self.token != Some(Token::Symbol('=')) {
+            panic!("unexpected token: expected '=', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let start = self.parse_expression();
+
+        if self.token != Some(Token::Symbol(',')) {
+            panic!("unexpected token: expected ',', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        self.push_binding(var_name.clone(), true);
+
+        let end = self.parse_expression();
+
+        // The step value is optional.
+        let step = if self.token == Some(Token::Symbol(',')) {
+            self.get_next_token();
+            Some(self.parse_expression())
+        } else {
+            None
+        };
+
+        if self.token != Some(Token::In) {
+            panic!("unexpected token: expected 'in', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        self.loop_depth += 1;
+        let body = self.parse_expression();
+        self.loop_depth -= 1;
+
+        self.pop_bindings(1);
+
+        Box::new(Expr::ForExpr(ForExpr {
+            var_name: var_name,
+            start: start,
+            end: end,
+            step: step,
+            body: body,
+        }))
+    }
+
+    // varexpr ::= 'var' id ('=' expression)? (',' id ('=' expression)?)* 'in' expression
+    fn parse_var(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::Var));
+        self.get_next_token();
+
+        let mut vars = Vec::new();
+        loop {
+            let name = match self.token.clone() {
+                Some(Token::Identifier(id)) => id,
+                _ => panic!("unexpected token: expected Identifier, got {:?}", self.token)
+            };
+            self.get_next_token();
+
+            // The initializer is optional.
+            let init = if self.token == Some(Token::Symbol('=')) {
+                self.get_next_token();
+                Some(self.parse_expression())
+            } else {
+                None
+            };
+            self.push_binding(name.clone(), true);
+            vars.push((name, init));
+
+            if self.token != Some(Token::Symbol(',')) {
+                break;
+            }
+            self.get_next_token();
+        }
+
+        if self.token != Some(Token::In) {
+            panic!("unexpected token: expected 'in', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let body = self.parse_expression();
+        self.pop_bindings(vars.len());
+
+        Box::new(Expr::VarExpr(VarExpr {
+            vars: vars,
+            body: body,
+        }))
+    }
+
+    // letexpr ::= 'let' (id | '(' id (',' id)+ ')') '=' expression 'in' expression
+    fn parse_let(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::Let));
+        self.get_next_token();
+
+        let names = if self.token == Some(Token::Symbol('(')) {
+            self.parse_tuple_pattern()
+        } else {
+            let name = match self.token.clone() {
+                Some(Token::Identifier(id)) => id,
+                _ => panic!("unexpected token: expected [ Identifier | '(' ], got {:?}", self.token)
+            };
+            self.get_next_token();
+            vec![name]
+        };
+
+        if self.token != Some(Token::Symbol('=')) {
+            panic!("unexpected token: expected '=', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let value = self.parse_expression();
+
+        if self.token != Some(Token::In) {
+            panic!("unexpected token: expected 'in', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        for name in names.iter() {
+            self.push_binding(name.clone(), false);
+        }
+        let body = self.parse_expression();
+        self.pop_bindings(names.len());
+
+        Box::new(Expr::LetExpr(LetExpr {
+            names: names,
+            value: value,
+            body: body,
+        }))
+    }
+
+    // The names a tuple is destructured into, at least two.
+    fn parse_tuple_pattern(&mut self) -> Vec<String> {
+        assert_eq!(self.token, Some(Token::Symbol('(')));
+        self.get_next_token();
+
+        let mut names: Vec<String> = Vec::new();
+        loop {
+            match self.token.clone() {
+                Some(Token::Identifier(id)) => {
+                    self.get_next_token();
+                    if names.contains(&id) {
+                        self.error(format!("duplicate name <{}> in tuple pattern", id));
+                    }
+                    names.push(id);
+                }
+                _ => panic!("unexpected token: expected Identifier, got {:?}", self.token)
+            }
+
+            match self.token {
+                Some(Token::Symbol(',')) => self.get_next_token(),
+                Some(Token::Symbol(')')) => {
+                    self.get_next_token();
+                    break;
+                }
+                _ => panic!("unexpected token: expected [ ',' | ')' ], got {:?}", self.token)
+            }
+        }
+
+        if names.len() < 2 {
+            self.error("tuple pattern with a single name, expected at least two".to_string());
+        }
+        names
+    }
+
+    // whileexpr ::= 'while' expression 'do' expression
+    fn parse_while(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::While));
+        self.get_next_token();
+
+        let cond = self.parse_expression();
+
+        if self.token != Some(Token::Do) {
+            panic!("unexpected token: expected 'do', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        self.loop_depth += 1;
+        let body = self.parse_expression();
+        self.loop_depth -= 1;
+
+        Box::new(Expr::WhileExpr(WhileExpr {
+            cond: cond,
+            body: body,
+        }))
+    }
+
+    // block ::= '{' (expression (';' expression)* ';'?)? '}'
+    fn parse_block(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::Symbol('{')));
+        self.get_next_token();
+
+        let mut exprs = Vec::new();
+        loop {
+            if self.token == Some(Token::Symbol('}')) {
+                self.get_next_token();
+                break;
+            }
+
+            exprs.push(self.parse_expression());
+
+            match self.token {
+                Some(Token::Symbol(';')) => self.get_next_token(),
+                Some(Token::Symbol('}')) => {}
+                _ => panic!("unexpected token: expected [ ';' | '}}' ], got {:?}", self.token)
+            }
+        }
+
+        Box::new(Expr::BlockExpr(BlockExpr { exprs: exprs }))
+    }
+
+    // array ::= '[' (expression (',' expression)*)? ']'
+    fn parse_array(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::LeftBracket));
+        self.get_next_token();
+
+        let mut elements = Vec::new();
+        loop {
+            if self.token == Some(Token::RightBracket) {
+                self.get_next_token();
+                break;
+            }
+
+            elements.push(self.parse_expression());
+
+            match self.token {
+                Some(Token::Symbol(',')) => self.get_next_token(),
+                Some(Token::RightBracket) => {}
+                _ => panic!("unexpected token: expected [ ',' | ']' ], got {:?}", self.token)
+            }
+        }
+
+        Box::new(Expr::ArrayExpr(ArrayExpr { elements: elements }))
+    }
+
+    // lambda ::= '\' (id annotation (',' id annotation)*)? '->' expression
+    fn parse_lambda(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::Symbol('\\')));
+        self.get_next_token();
+
+        let mut args = Vec::new();
+        let mut arg_types = Vec::new();
+        loop {
+            match self.token.clone() {
+                Some(Token::Identifier(id)) => {
+                    self.get_next_token();
+                    args.push(id);
+                    arg_types.push(self.parse_type_annotation());
+
+                    if self.token == Some(Token::Symbol(',')) {
+                        self.get_next_token();
+                    }
+                }
+                Some(Token::Operator(ref op)) if op == "->" => {
+                    self.get_next_token();
+                    break;
+                }
+                _ => panic!("unexpected token: expected '->', got {:?}", self.token)
+            }
+        }
+
+        // The body becomes a function of its own, so the loops around the
+        // lambda cannot be broken out of from it.
+        for arg in args.iter() {
+            self.push_binding(arg.clone(), true);
+        }
+        let loop_depth = self.loop_depth;
+        self.loop_depth = 0;
+        let body = self.parse_expression();
+        self.loop_depth = loop_depth;
+        self.pop_bindings(args.len());
+
+        // The lambdas nested in the body are numbered first.
+        self.nested += 1;
+        Box::new(Expr::LambdaExpr(LambdaExpr {
+            id: self.nested,
+            args: args,
+            arg_types: arg_types,
+            return_type: self.type_checker.new_variable(),
+            body: body,
+        }))
+    }
+
+    // defexpr ::= 'def' id '(' arg* ')' annotation expression 'in' expression
+    fn parse_nested_definition(&mut self) -> Box<Expr> {
+        assert_eq!(self.token, Some(Token::Def));
+        self.get_next_token();
+
+        // Operators are global, so they cannot be nested.
+        match self.token {
+            Some(Token::Identifier(_)) => {}
+            _ => panic!("unexpected token: expected Identifier, got {:?}", self.token)
+        }
+        let proto = self.parse_prototype();
+
+        // Like a lambda, the body is a function of its own.
+        self.push_binding(proto.name.clone(), false);
+        for arg in proto.args.iter() {
+            self.push_binding(arg.clone(), true);
+        }
+        let loop_depth = self.loop_depth;
+        self.loop_depth = 0;
+        let body = self.parse_expression();
+        self.loop_depth = loop_depth;
+        self.pop_bindings(proto.args.len());
+
+        // Numbered like a lambda, after the functions nested in its body.
+        self.nested += 1;
+        let id = self.nested;
+
+        if self.token != Some(Token::In) {
+            panic!("unexpected token: expected 'in', got {:?}", self.token)
+        }
+        self.get_next_token();
+
+        let rest = self.parse_expression();
+        self.pop_bindings(1);
+
+        Box::new(Expr::DefExpr(DefExpr {
+            id: id,
+            function: Function {
+                proto: proto,
+                body: body,
+            },
+            body: rest,
+        }))
+    }
+
+    // binoprhs ::= ('+' unary)*
+    fn parse_binoprhs(&mut self, mut lhs: Box<Expr>, lhs_precedence: i32) -> Box<Expr> {
         loop {
             let precedence = self.get_token_precedence();
             if precedence.1 < lhs_precedence {
@@ -273,13 +1314,26 @@ impl<'b> Parser<'b> {
             }
 
             self.get_next_token();
-            let mut rhs = self.parse_primary();
+            let mut rhs = self.parse_unary();
 
             // If BinOp binds less tightly with RHS than the operator after RHS,
-            // let the pending operator take RHS as its LHS.
+            // let the pending operator take RHS as its LHS. A right associative
+            // operator also takes RHS when the next operator binds equally.
             let next_precedence = self.get_token_precedence();
-            if precedence.1 < next_precedence.1 {
-                rhs = self.parse_binoprhs(rhs, precedence.1 + 1);
+            let right_associative = is_right_associative(&precedence.0);
+            if precedence.1 < next_precedence.1 || (right_associative && precedence.1 == next_precedence.1) {
+                let rhs_precedence = if right_associative { precedence.1 } else { precedence.1 + 1 };
+                rhs = self.parse_binoprhs(rhs, rhs_precedence);
+            }
+
+            if precedence.0 == "=" {
+                match *lhs {
+                    Expr::VariableExpr(ref v) if self.is_immutable(&v.name) => {
+                        self.error(format!("invalid assignment: <{}> is bound by 'let' or 'def' and cannot be assigned", v.name))
+                    }
+                    Expr::VariableExpr(_) | Expr::IndexExpr(_) | Expr::FieldExpr(_) => {}
+                    _ => self.error("invalid assignment: left side of '=' is not a variable, array element or field".to_string())
+                }
             }
 
             lhs = Box::new(Expr::BinaryExpr(BinaryExpr {
@@ -290,23 +1344,49 @@ impl<'b> Parser<'b> {
         }
     }
 
-    fn get_token_precedence(&self) -> (char, i32) {
-        match self.token {
-            Some(Token::Symbol(op)) if BINOP_PRECEDENCE.contains_key(&op) => {
-                (op, *BINOP_PRECEDENCE.get(&op).unwrap())
-            }
-            _ => (' ', -1)
+    fn get_token_precedence(&self) -> (String, i32) {
+        let op = match self.token.clone() {
+            Some(Token::Symbol(op)) => op.to_string(),
+            Some(Token::Operator(op)) => op,
+            _ => return (String::new(), -1)
+        };
+        match self.binop_precedence.get(&op) {
+            Some(precedence) => (op, *precedence),
+            None => (String::new(), -1)
         }
     }
 }
 
+#[inline]
+fn is_right_associative(op: &str) -> bool {
+    op == "=" || op == "^"
+}
+
+// Characters which can be defined as operators, the other symbols being
+// punctuation.
+#[inline]
+fn is_operator_char(c: char) -> bool {
+    "!$%&*+-/:<=>?@^|~".contains(c)
+}
+
 lazy_static! {
-    static ref BINOP_PRECEDENCE: HashMap<char, i32> = {
+    static ref BINOP_PRECEDENCE: HashMap<String, i32> = {
         let mut m = HashMap::new();
-        m.insert('<', 10);
-        m.insert('+', 20);
-        m.insert('-', 20);
-        m.insert('*', 40); // highest
+        m.insert("=".to_string(), 2); // lowest
+        m.insert("||".to_string(), 4);
+        m.insert("&&".to_string(), 6);
+        m.insert("==".to_string(), 8);
+        m.insert("!=".to_string(), 8);
+        m.insert("<".to_string(), 10);
+        m.insert(">".to_string(), 10);
+        m.insert("<=".to_string(), 10);
+        m.insert(">=".to_string(), 10);
+        m.insert("+".to_string(), 20);
+        m.insert("-".to_string(), 20);
+        m.insert("*".to_string(), 40);
+        m.insert("/".to_string(), 40);
+        m.insert("%".to_string(), 40);
+        m.insert("^".to_string(), 60); // highest
         m
     };
 }
@@ -314,12 +1394,33 @@ lazy_static! {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::mem;
+    use std::os::raw::c_char;
+    use llvm::execution_engine::*;
+
+    // Compile the module and get the address of a generated function, to
+    // call it from the test. The execution engine is never disposed.
+    fn function_address(parser: &Parser, name: &str) -> usize {
+        runtime::register();
+        unsafe {
+            LLVMLinkInMCJIT();
+            let mut execution_engine: LLVMExecutionEngineRef = null_mut();
+            let mut error: *mut i8 = null_mut();
+            if LLVMCreateExecutionEngineForModule(&mut execution_engine, parser.module(), &mut error) != 0 {
+                panic!("create execution engine failed: {}", CString::from_raw(error).into_string().unwrap());
+            }
+            LLVMGetFunctionAddress(execution_engine, CString::new(name).unwrap().into_raw()) as usize
+        }
+    }
 
     #[test]
     fn test_parse() {
         let mut parser = Parser::new(r"
-# An incomplete (and wrong) example, because if-stat is not supported for now
+# Compute the x'th fibonacci number.
 def fib(x)
+  if x < 3 then
+    1
+  else
     fib(x-1)+fib(x-2)
 ");
 
@@ -331,12 +1432,933 @@ def fib(x)
     #[test]
     fn test_codegen() {
         let mut parser = Parser::new(r"
-# An incomplete (and wrong) example, because if-stat is not supported for now
+# Compute the x'th fibonacci number.
 def fib(x)
+  if x < 3 then
+    1
+  else
     fib(x-1)+fib(x-2)
 ");
 
         parser.parse();
         parser.codegen.iter().for_each(|c| println!("{}", c));
     }
+
+    #[test]
+    fn test_for() {
+        let mut parser = Parser::new(r"
+extern putchard(char);
+
+# Print n stars.
+def stars(n)
+  for i = 1, i < n, 1.0 in
+    putchard(42);
+
+# The loop variable shadows the parameter inside the body only.
+def shadow(i)
+  (for i = 1, i < 10 in i) + i;
+");
+
+        parser.parse();
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_binary_operator() {
+        let mut parser = Parser::new(r"
+# Logical or, binding looser than '<'.
+def binary| 5 (lhs rhs)
+  if lhs then 1 else if rhs then 1 else 0;
+
+def outside(x) x < 1 | x > 5;
+
+# Builtin operators cannot be redefined.
+def binary> 20 (lhs rhs)
+  rhs < lhs;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics, vec!["cannot redefine builtin operator <>>".to_string()]);
+        assert_eq!(parser.codegen.len(), 2);
+        assert_eq!(parser.binop_precedence.get("|"), Some(&5));
+        assert_eq!(parser.binop_precedence.get(">"), Some(&10));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_unary_operator() {
+        let mut parser = Parser::new(r"
+def unary~ (v)
+  if v then 0 else 1;
+
+def unary? (v)
+  v > 0;
+
+def test(x) ~(x < 0) + -x - - -x + double(!?x);
+
+# Builtin operators cannot be redefined.
+def unary! (v) v;
+
+def unary- (v) 0 - v;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics, vec![
+            "cannot redefine builtin operator <!>".to_string(),
+            "cannot redefine builtin operator <->".to_string(),
+        ]);
+        assert_eq!(parser.codegen.len(), 3);
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected token")]
+    fn test_unary_punctuation() {
+        let mut parser = Parser::new(r"
+def f(x) x + ;
+");
+
+        parser.parse();
+    }
+
+    #[test]
+    fn test_var() {
+        let mut parser = Parser::new(r"
+def fibi(x)
+  var a = 1, b = 1, c in
+  (for i = 3, i < x in
+     var c = a + b in
+     b) + b;
+
+# The initializer sees the outer binding.
+def shadow(a) var a = a * 2, b in a + b;
+");
+
+        parser.parse();
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_assignment() {
+        let mut parser = Parser::new(r"
+# ':' sequences expressions, evaluating to its right operand.
+def binary : 1 (x y) y;
+
+def fibi(x)
+  var a = 1, b = 1, c in
+  (for i = 3, i < x in
+     c = a + b :
+     a = b :
+     b = c) :
+  b;
+
+def chain(x) var a, b in a = b = x + 1;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_while() {
+        let mut parser = Parser::new(r"
+def binary : 1 (x y) y;
+
+# Sum of the odd numbers below x, stopping once the sum exceeds 100.
+def sumodd(x)
+  var i = 0, sum = 0 in
+  (while i < x do
+     i = i + 1 :
+     (if 100 < sum then break else 0) :
+     (for j = 0, j < 2 in if j < 1 then continue else sum = sum + i) :
+     if i - 2 * (i * 0.5) < 0.5 then continue else sum = sum + i) :
+  sum;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_break_outside_loop() {
+        let mut parser = Parser::new(r"
+def bad(x) if x then break else continue;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics, vec![
+            "'break' outside of a loop".to_string(),
+            "'continue' outside of a loop".to_string(),
+        ]);
+        assert!(parser.codegen.is_empty());
+    }
+
+    #[test]
+    fn test_block() {
+        let mut parser = Parser::new(r"
+extern putchard(char);
+
+def printstars(n) {
+  for i = 0, i < n in putchard(42);
+  putchard(10);
+  n
+};
+
+def empty(x) {};
+
+def nested(x) { var y = x in { y = y + 1; }; { x; { x * 2 } } };
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_comparison() {
+        let mut parser = Parser::new(r"
+def clamp(x) if x <= 0 then 0 else if x >= 1 then 1 else x;
+
+def eq(a b) a == b;
+
+def ne(a b) a != b;
+
+# Equality binds looser than the relational operators.
+def xor(a b) a > 0 != b > 0;
+");
+
+        parser.parse();
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut parser = Parser::new(r"
+def mean(a b) (a + b) / 2;
+
+def isodd(n) n % 2 == 1;
+
+# '^' is right associative: 2 ^ 3 ^ 2 is 2 ^ 9.
+def tower(x) x * 2 ^ 3 ^ 2;
+");
+
+        parser.parse();
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_logical() {
+        let mut parser = Parser::new(r"
+extern sqrt(x);
+
+# The square root is only taken for non-negative x.
+def check(x) x >= 0 && sqrt(x) < 2;
+
+def outside(x lo hi) x < lo || x > hi && hi >= lo;
+");
+
+        parser.parse();
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_builtin_unary() {
+        let mut parser = Parser::new(r"
+def sub(a b) a - -b;
+
+def neg(x) -x * -2;
+
+def not(x) !bool(x) || !!(x - 1 > 0);
+
+# '^' binds tighter than '-': -x ^ 2 is -(x ^ 2).
+def square(x) -x ^ 2;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+
+        let square: extern "C" fn(f64) -> f64 = unsafe { mem::transmute(function_address(&parser, "square")) };
+        assert_eq!(square(3.0), -9.0);
+    }
+
+    #[test]
+    fn test_let() {
+        let mut parser = Parser::new(r"
+extern sin(x);
+
+def square(x) let y = sin(x) in y * y;
+
+# Shadowing a let-bound name with 'var' makes it mutable again.
+def shadow(x) let x = x + 1 in var x = x in x = x * 2;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_let_assignment() {
+        let mut parser = Parser::new(r"
+def bad(x) let y = x in y = 2;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 1);
+        assert!(parser.codegen.is_empty());
+    }
+
+    #[test]
+    fn test_int() {
+        let mut parser = Parser::new(r"
+# Integer literals take the type of the value they are used with.
+def half(x) var n = int(x) in double(n / 2 + n % 2 * 0);
+
+def count(x)
+  var i = int(0), n = int(x) in {
+    while i * i < n do i = i + 1;
+    double(i - -1)
+  };
+
+def pick(x) double(if x < 0 then -1 else int(x));
+");
+
+        parser.parse();
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_int_division() {
+        let mut parser = Parser::new(r"
+def divide(x: int, y: int) x / y + x % y;
+
+def big() 99999999999999999999;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics, vec!["integer literal out of range: 99999999999999999999".to_string()]);
+        assert!(parser.codegen[0].contains("call void @division_error()"));
+
+        let divide: extern "C" fn(i64, i64) -> i64 = unsafe { mem::transmute(function_address(&parser, "divide")) };
+        assert_eq!(divide(7, 2), 4);
+        assert_eq!(runtime::take_error(), None);
+        assert_eq!(divide(7, 0), 0);
+        assert_eq!(runtime::take_error(), Some("integer division by zero".to_string()));
+        assert_eq!(divide(-7, -1), 7);
+        assert_eq!(divide(i64::MIN, -1), i64::MIN);
+        assert_eq!(runtime::take_error(), None);
+    }
+
+    #[test]
+    fn test_bool() {
+        let mut parser = Parser::new(r"
+def positive(x) x > 0;
+
+def between(x, lo, hi): bool lo <= x && x <= hi;
+
+def count(flag: bool, n) if flag == true then n else int(flag);
+
+def sign(x) double(x > 0) - double(x < 0);
+
+def truthy(x) bool(x) != false;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[0].contains("define i1 @positive(double %x)"));
+        assert!(parser.codegen[2].contains("define i64 @count(i1 %flag, i64 %n)"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_bool_errors() {
+        let mut parser = Parser::new(r"
+def bad1(x) if x * 2 then 1 else 2;
+
+def bad2(x) while x + 1 do x;
+
+def bad3(b) if b then -b else b;
+
+def bad4(x) true + 1;
+
+def bad5(x) !(x * 2);
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 5);
+        assert!(parser.codegen.is_empty());
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut parser = Parser::new(r#"
+def greet(name) print(concat(concat("Hello, ", name), "!\n"));
+
+def longer(a, b) if len(a) < len(b) then b else a;
+
+def repeat(s: string, n: int): string var r = "", i = int(0) in {
+  while i < n do {
+    r = concat(r, s);
+    i = i + 1
+  };
+  r
+};
+
+greet("world");
+"#);
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[0].contains("define double @greet(i8* %name)"));
+        assert!(parser.codegen[1].contains("define i8* @longer(i8* %a, i8* %b)"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+
+        let repeat: extern "C" fn(*const c_char, i64) -> *const c_char = unsafe { mem::transmute(function_address(&parser, "repeat")) };
+        let s = CString::new("ab").unwrap();
+        for (n, expected) in [(0, ""), (1, "ab"), (3, "ababab")].iter() {
+            let r = unsafe { CStr::from_ptr(repeat(s.as_ptr(), *n)) };
+            assert_eq!(r.to_str().unwrap(), *expected);
+        }
+    }
+
+    #[test]
+    fn test_string_errors() {
+        let mut parser = Parser::new(r#"
+def bad1(s) s + "!";
+
+def bad2(s: string) s == "a";
+
+def bad3(x) len(x) + print(x);
+
+def bad4(x) double("1.5");
+
+def bad5() "\q";
+
+def bad6() "abc"#);
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 6);
+        assert_eq!(parser.diagnostics[4], "unknown escape sequence in string literal: \\q");
+        assert_eq!(parser.diagnostics[5], "unterminated string literal");
+        assert!(parser.codegen.is_empty());
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut parser = Parser::new(r"
+def sum(a) var s = 0 in { for i = int(0), i < len(a) - 1 in s = s + a[i]; s };
+
+def scale(a, k) { for i = int(0), i < len(a) - 1 in a[i] = a[i] * k; a };
+
+def squares(n: int) var a = array(n) in { for i = int(0), i < n - 1 in a[i] = double(i * i); a };
+
+sum(scale([1, 2.5, -3], 2)) + squares(4)[3];
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[0].contains("define double @sum({ i64, double* }* %a)"));
+        assert!(parser.codegen[0].contains("call void @index_error"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_array_errors() {
+        let mut parser = Parser::new(r#"
+def bad1(a) a[1.5];
+
+def bad2(x) x[0] + len(x) + x;
+
+def bad3(a) [1, a, "b"];
+
+def bad4(a: array) a[0] = true;
+
+def bad5(n) array(n) = 1;
+"#);
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 5);
+        assert!(parser.codegen.is_empty());
+    }
+
+    #[test]
+    fn test_structs() {
+        let mut parser = Parser::new(r"
+struct Point { x, y };
+
+struct Circle { cx, cy, r };
+
+# p is a Point, the only struct with a field x.
+def norm(p) (p.x ^ 2 + p.y ^ 2) ^ 0.5;
+
+def move(p: Point, dx, dy) { p.x = p.x + dx; p.y = p.y + dy; p };
+
+def area(c: Circle) 3.14159 * c.r * c.r;
+
+norm(move(Point(1, 2), 2, 2));
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[0].contains("define %Point* @Point(double %x, double %y)"));
+        assert!(parser.codegen[2].contains("define double @norm(%Point* %p)"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_struct_errors() {
+        let mut parser = Parser::new(r"
+struct A { x, y, x };
+
+struct B { u, v };
+
+struct C { u };
+
+struct B { w };
+
+def bad1(b: B) b.w;
+
+def bad2(b) b.u;
+
+def bad3(x: int) x.u;
+
+def bad4(b: B) b.u = true;
+
+def good(b: B) B(b.v, b.u);
+
+# A is invalid, so it is not defined.
+def bad5(a: A) a.y;
+
+def bad6(a) a.x;
+
+# A struct's constructor and a function cannot share a name.
+def Point(x) x;
+
+struct Point { x, y };
+
+def C(u) u;
+
+extern B(u v);
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 11);
+        assert_eq!(parser.diagnostics[6], "unknown type <A>");
+        assert_eq!(parser.diagnostics[7], "unknown field <x>");
+        assert_eq!(parser.diagnostics[8], "redefinition of <Point>");
+        assert_eq!(parser.diagnostics[9], "redefinition of <C>");
+        assert_eq!(parser.diagnostics[10], "redefinition of <B>");
+        assert_eq!(parser.codegen.len(), 4);
+    }
+
+    #[test]
+    fn test_type_annotations() {
+        let mut parser = Parser::new(r"
+extern putchard(c: double): double;
+
+def isqrt(n: int): int
+  var i = int(0) in {
+    while (i + 1) * (i + 1) <= n do i = i + 1;
+    i
+  };
+
+def mean(a: double, b: double) (a + b) / 2;
+
+def hypot(x: int, y: int): double double(isqrt(x * x + y * y));
+
+isqrt(17) * 2;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_type_inference() {
+        let mut parser = Parser::new(r"
+def half(n: int): int n / 2;
+
+# x is passed to half, so it is an int, and so is the result.
+def quarter(x) half(half(x));
+
+# Nothing constrains x, so it is a double.
+def twice(x) x * 2;
+
+def fact(n) if n < 2 then 1 else n * fact(n - int(1));
+
+def mix(a, b) double(a + b) * twice(b);
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[1].contains("define i64 @quarter(i64 %x)"));
+        assert!(parser.codegen[2].contains("define double @twice(double %x)"));
+        assert!(parser.codegen[3].contains("define i64 @fact(i64 %n)"));
+        assert!(parser.codegen[4].contains("define double @mix(double %a, double %b)"));
+    }
+
+    #[test]
+    fn test_type_errors() {
+        let mut parser = Parser::new(r"
+def half(n: int): int n / 2;
+
+def bad1(x) half(x) + x * 1.5;
+
+def bad2(n: int): double n + 1;
+
+def bad3(n: int) var x = 1.5 in x = n;
+
+def bad4(n: int) if n then n else 1.5;
+
+def bad5(x: float) x;
+
+def good(x) double(half(int(x)));
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 5);
+        assert_eq!(parser.codegen.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_assignment() {
+        let mut parser = Parser::new(r"
+def bad(x) x + 1 = 2;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 1);
+        assert!(parser.codegen.is_empty());
+    }
+
+    #[test]
+    fn test_function_values() {
+        let mut parser = Parser::new(r"
+def square(x) x * x;
+
+# f is called with a double, so it is a function from double to double.
+def integrate(f, a, b, n: int)
+  var sum = 0, i = int(0) in {
+    while i < n do {
+      sum = sum + f(a + (b - a) * (double(i) + 0.5) / double(n));
+      i = i + 1
+    };
+    sum * (b - a) / double(n)
+  };
+
+def twice(f: (int) -> int, x: int) f(f(x));
+
+def cube(x) x * x * x;
+
+def pick(small: bool) if small then square else cube;
+
+pick(false)(2) + integrate(square, 0, 3, 100);
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[1].contains("define double @integrate({ double (i8*, double)*, i8* }* %f, double %a, double %b, i64 %n)"));
+        assert!(parser.codegen[2].contains("define i64 @twice({ i64 (i8*, i64)*, i8* }* %f, i64 %x)"));
+        assert!(parser.codegen[4].contains("define { double (i8*, double)*, i8* }* @pick(i1 %small)"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_function_value_errors() {
+        let mut parser = Parser::new(r"
+def half(n: int): int n / 2;
+
+def bad1(f: (double) -> double) f(1, 2);
+
+def bad2(x: double) x(1);
+
+def bad3(f: (int) -> int) f(1.5);
+
+def bad4(f) f(half) + f(1.5);
+
+def good(f: (int) -> int) f(half(4));
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 4);
+        assert_eq!(parser.codegen.len(), 2);
+    }
+
+    #[test]
+    fn test_lambdas() {
+        let mut parser = Parser::new(r"
+def scale(k) \x -> x * k;
+
+# The inner lambda captures x from the outer one, and n through it.
+def adder(n: int) \x: int -> \y: int -> x + y + n;
+
+def apply(f: (double) -> double, x) f(x);
+
+apply(scale(3), 2) + double(adder(1)(2)(3)) + (\x -> x + 1)(1);
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[0].contains("define double @lambda.1(i8* %env, double %x)"));
+        assert!(parser.codegen[1].contains("define { double (i8*, double)*, i8* }* @scale(double %k)"));
+        assert!(parser.codegen[2].contains("define i64 @lambda.2(i8* %env, i64 %y)"));
+        assert!(parser.codegen[3].contains("define { i64 (i8*, i64)*, i8* }* @lambda.3(i8* %env, i64 %x)"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_top_level_lambda() {
+        let mut parser = Parser::new(r"
+(\x -> x)(1.5);
+
+\x -> x;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[1].contains("define double @0()"));
+        assert!(parser.codegen[3].contains("define { double (i8*, double)*, i8* }* @1()"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_lambda_errors() {
+        let mut parser = Parser::new(r"
+def bad1() \x -> x + true;
+
+def bad2() (\x -> x)(1, 2);
+
+def bad3() for i = 0, i < 3 in (\x -> break)(1);
+
+def bad4(n: int) (\x: double -> x)(n);
+
+def good(k) (\x -> x * k)(2);
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 4);
+        assert_eq!(parser.codegen.len(), 2);
+    }
+
+    #[test]
+    fn test_nested_definitions() {
+        let mut parser = Parser::new(r"
+def apply(f: (double) -> double, x) f(x);
+
+# sum uses n, and x through scale, which are passed to it.
+def outer(x, n: int)
+  def scale(y) y * x in
+  def sum(i: int) if i > n then 0 else scale(double(i)) + sum(i + 1) in
+  sum(int(1)) + apply(scale, 2);
+
+def counter(n: int)
+  var count = int(0) in
+  def bump() count + n in {
+    count = 5;
+    bump()
+  };
+
+outer(2, 3) + double(counter(1));
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[1].contains("define double @scale.1.adapter(i8* %env, double %y)"));
+        assert!(parser.codegen[2].contains("define double @scale.1(double %x, double %y)"));
+        assert!(parser.codegen[3].contains("define double @sum.2(i64 %n, double %x, i64 %i)"));
+        assert!(parser.codegen[4].contains("define double @outer(double %x, i64 %n)"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_nested_definition_errors() {
+        let mut parser = Parser::new(r"
+def bad1(x) def h(y) x + y in var x = 1 in h(2);
+
+def bad2(x) def h(y) x + y in h = 3;
+
+def bad3(x) def h(y) y in h(true) + h(1);
+
+def bad4(x) for i = 0, i < x in def h(y) break in h(1);
+
+def good(x) def h(y) x + y in var y = 1 in h(y);
+
+# inc gets a copy of n, so its assignment would be lost.
+def bad5() var n = 0 in def inc() n = n + 1 in { inc(); inc(); n };
+
+def bad6() var n = 0 in (\x -> n = x)(1);
+
+# f keeps a copy of k, so it would not see the assignment.
+def bad7() var k = 1 in let f = \x -> x * k in { k = 2; f(1) };
+
+def bad8() var k = 1 in def g(x) x * k in let f = g in { k = 2; f(1) };
+
+# Called directly, g is passed the current k.
+def good2() var k = 1 in def g(x) x * k in { k = 2; g(1) };
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 8);
+        assert_eq!(parser.diagnostics[4], "invalid assignment: <n> is captured by a closure and cannot be assigned");
+        assert_eq!(parser.diagnostics[5], "invalid assignment: <n> is captured by a closure and cannot be assigned");
+        assert_eq!(parser.diagnostics[6], "invalid assignment: <k> is captured by a closure and cannot be assigned");
+        assert_eq!(parser.diagnostics[7], "invalid assignment: <k> is captured by a closure and cannot be assigned");
+        assert_eq!(parser.codegen.len(), 4);
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut parser = Parser::new(r"
+const RATE = 0.25;
+const STEPS = int(4) * 2;
+const LONG = !(STEPS > 5);
+global total = 0;
+global count = int(0);
+
+def add(x) {
+  total = total + x * RATE;
+  count = count + 1;
+  total
+};
+
+# A parameter shadows the constant.
+def shadow(RATE) RATE = RATE + 1;
+
+add(1) + double(STEPS);
+
+# Integer division is folded too.
+const LEFT = STEPS / 3 % -2;
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert_eq!(parser.codegen[0], "double 2.500000e-01");
+        assert_eq!(parser.codegen[1], "i64 8");
+        assert_eq!(parser.codegen[2], "i1 false");
+        assert_eq!(parser.codegen[3], "@total = global double 0.000000e+00");
+        assert_eq!(parser.codegen[4], "@count = global i64 0");
+        assert!(parser.codegen[5].contains("store double %addtmp, double* @total"));
+        assert!(parser.codegen[5].contains("store i64 %addtmp3, i64* @count"));
+        assert!(parser.codegen[6].contains("fadd double %RATE, 1.000000e+00"));
+        assert!(parser.codegen[7].contains("fadd double %calltmp, 8.000000e+00"));
+        assert_eq!(parser.codegen[8], "i64 0");
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+    }
+
+    #[test]
+    fn test_global_errors() {
+        let mut parser = Parser::new(r"
+global total = 0;
+const RATE = 0.5;
+
+global bad1 = total + 1;
+
+const bad2 = RATE > 0 && true;
+
+global total = 1;
+
+def bad4() RATE = 1;
+
+def bad5() total = true;
+
+def bad6() total(1);
+
+def good() total = RATE;
+
+const bad7 = int(1) / int(0);
+
+const bad8 = int(RATE) % 0;
+
+def bad9() bad7;
+
+const bad10 = (-9223372036854775807 - int(1)) / -1;
+
+global bad11 = (-9223372036854775807 - int(1)) % -1;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 11);
+        assert_eq!(parser.diagnostics[6], "invalid value for <bad7>: integer division by zero");
+        assert_eq!(parser.diagnostics[7], "invalid value for <bad8>: integer division by zero");
+        assert_eq!(parser.diagnostics[9], "invalid value for <bad10>: integer division overflow");
+        assert_eq!(parser.diagnostics[10], "invalid value for <bad11>: integer division overflow");
+        assert_eq!(parser.codegen.len(), 3);
+    }
+
+    #[test]
+    fn test_tuples() {
+        let mut parser = Parser::new(r"
+def divmod(x: int, y: int) : (int, int) (x / y, x % y);
+
+# The loop checks its condition after the body, which runs with i up to
+# len(a) - 1, and at least once.
+def minmax(a: array)
+  if len(a) == 0 then (0, 0) else
+  var lo = a[0], hi = a[0] in {
+    if len(a) > 1 then
+      for i = int(1), i < len(a) - 1 in {
+        lo = if a[i] < lo then a[i] else lo;
+        hi = if a[i] > hi then a[i] else hi
+      }
+    else 0;
+    (lo, hi)
+  };
+
+def swap(p) let (a, b) = p in (b, a);
+
+def grouped(x) (x + 1) * 2;
+
+let (q, r) = divmod(17, 5) in
+let (lo, hi) = minmax([3, 1, 4]) in
+let (s, t) = swap((0.5, 2)) in
+double(q + r) + hi - lo + s - t;
+
+def sample(n: int) if n == 0 then minmax([]) else if n == 1 then minmax([2]) else minmax([3, 1, 4, 1, 5]);
+");
+
+        parser.parse();
+        assert!(parser.diagnostics.is_empty());
+        assert!(parser.codegen[0].contains("define { i64, i64 } @divmod(i64 %x, i64 %y)"));
+        assert!(parser.codegen[0].contains("insertvalue { i64, i64 }"));
+        assert!(parser.codegen[1].contains("define { double, double } @minmax({ i64, double* }* %a)"));
+        assert!(parser.codegen[2].contains("define { double, double } @swap({ double, double } %p)"));
+        assert!(parser.codegen[3].contains("define double @grouped(double %x)"));
+        assert!(parser.codegen[4].contains("extractvalue { i64, i64 }"));
+        parser.codegen.iter().for_each(|c| println!("{}", c));
+
+        #[repr(C)]
+        #[derive(Debug, PartialEq)]
+        struct Pair(f64, f64);
+        let sample: extern "C" fn(i64) -> Pair = unsafe { mem::transmute(function_address(&parser, "sample")) };
+        assert_eq!(sample(0), Pair(0.0, 0.0));
+        assert_eq!(sample(1), Pair(2.0, 2.0));
+        assert_eq!(sample(5), Pair(1.0, 5.0));
+    }
+
+    #[test]
+    fn test_tuple_errors() {
+        let mut parser = Parser::new(r"
+def bad1(x) let (a, b) = x + 1 in a;
+
+def bad2() let (a, b) = (1, 2, 3) in a;
+
+def bad3() let (a, a) = (1, 2) in a;
+
+def bad4() let (a) = (1, 2) in a;
+
+def bad5() : (int, bool) (1, 2);
+
+def bad6() (1, 2) + (3, 4);
+
+def good(p: (int, bool)) let (n, b) = p in if b then n else 0;
+");
+
+        parser.parse();
+        assert_eq!(parser.diagnostics.len(), 6);
+        assert_eq!(parser.codegen.len(), 1);
+    }
 }
@@ -0,0 +1,115 @@
+use std::ffi::{CStr, CString};
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_void};
+use std::sync::Mutex;
+
+use llvm::support::LLVMAddSymbol;
+
+use crate::ast::{Type, Prototype};
+
+// Prototypes of the runtime functions, declared in every module.
+pub fn prototypes() -> Vec<Prototype> {
+    vec![
+        prototype("print", &[("s", Type::Str)], Type::Double),
+        prototype("concat", &[("a", Type::Str), ("b", Type::Str)], Type::Str),
+        prototype("array", &[("n", Type::Int)], Type::Array),
+    ]
+}
+
+fn prototype(name: &str, args: &[(&str, Type)], return_type: Type) -> Prototype {
+    Prototype {
+        name: name.to_string(),
+        args: args.iter().map(|(arg, _)| arg.to_string()).collect(),
+        arg_types: args.iter().map(|(_, ty)| ty.clone()).collect(),
+        return_type: return_type,
+        env: None,
+    }
+}
+
+// Make the runtime functions visible to JIT'd code, which looks them up by
+// name like any other external symbol.
+pub fn register() {
+    let symbols: [(&str, *mut c_void); 5] = [
+        ("print", print as *mut c_void),
+        ("concat", concat as *mut c_void),
+        ("array", array as *mut c_void),
+        ("index_error", index_error as *mut c_void),
+        ("division_error", division_error as *mut c_void),
+    ];
+    for (name, address) in symbols.iter() {
+        unsafe {
+            LLVMAddSymbol(CString::new(*name).unwrap().into_raw(), *address);
+        }
+    }
+}
+
+// Strings are NUL-terminated, as in C.
+
+extern "C" fn print(s: *const c_char) -> f64 {
+    let s = unsafe { CStr::from_ptr(s) };
+    let mut stdout = io::stdout();
+    stdout.write_all(s.to_bytes()).unwrap();
+    stdout.flush().unwrap();
+    0.0
+}
+
+// The result is never freed.
+extern "C" fn concat(a: *const c_char, b: *const c_char) -> *mut c_char {
+    let mut bytes = unsafe { CStr::from_ptr(a) }.to_bytes().to_vec();
+    bytes.extend_from_slice(unsafe { CStr::from_ptr(b) }.to_bytes());
+    CString::new(bytes).unwrap().into_raw()
+}
+
+// Layout of an array, see Parser::get_array_type.
+#[repr(C)]
+pub struct Array {
+    len: i64,
+    data: *mut f64,
+}
+
+// Allocate an array of n zeros. Arrays are never freed either.
+extern "C" fn array(n: i64) -> *mut Array {
+    if n < 0 {
+        report_error(format!("invalid array length {}", n));
+    }
+
+    let len = n.max(0);
+    let data = Box::into_raw(vec![0.0; len as usize].into_boxed_slice());
+    Box::into_raw(Box::new(Array {
+        len: len,
+        data: data as *mut f64,
+    }))
+}
+
+// Called by the generated code instead of accessing an element out of range.
+extern "C" fn index_error(index: i64, len: i64) {
+    report_error(format!("index {} out of range for array of length {}", index, len));
+}
+
+// Called by the generated code instead of an integer division by zero.
+extern "C" fn division_error() {
+    report_error("integer division by zero".to_string());
+}
+
+pub unsafe fn array_to_string(array: *const Array) -> String {
+    let elements = std::slice::from_raw_parts((*array).data, (*array).len as usize);
+    let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+    format!("[{}]", elements.join(", "))
+}
+
+lazy_static! {
+    // The first error raised while running the current top-level expression
+    static ref ERROR: Mutex<Option<String>> = Mutex::new(None);
+}
+
+fn report_error(message: String) {
+    let mut error = ERROR.lock().unwrap();
+    if error.is_none() {
+        *error = Some(message);
+    }
+}
+
+// Take the error raised since the last call, if any.
+pub fn take_error() -> Option<String> {
+    ERROR.lock().unwrap().take()
+}
@@ -0,0 +1,843 @@
+use std::collections::HashMap;
+
+use crate::closure;
+use crate::ast::{Type, Expr, ArrayExpr, TupleExpr, IndexExpr, FieldExpr, UnaryExpr, BinaryExpr, CallExpr, LambdaExpr, DefExpr, IfExpr, ForExpr, VarExpr, LetExpr, WhileExpr, BlockExpr, Prototype, Function, StructDef, GlobalDef, is_untyped, is_builtin_binary};
+
+type CheckResult = Result<Type, String>;
+
+// Kinds of types some operations are restricted to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Class {
+    // int or double
+    Number,
+    // int, double or bool, which can be compared and converted
+    Scalar,
+    // string or array, which have a length
+    Sized,
+}
+
+impl Class {
+    fn contains(self, ty: &Type) -> bool {
+        match self {
+            Class::Number => *ty == Type::Int || *ty == Type::Double,
+            Class::Scalar => *ty == Type::Int || *ty == Type::Double || *ty == Type::Bool,
+            Class::Sized => *ty == Type::Str || *ty == Type::Array,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Class::Number => "a number",
+            Class::Scalar => "a number or bool",
+            Class::Sized => "a string or array",
+        }
+    }
+}
+
+// Static type checking, run on each top-level item before codegen.
+//
+// The rules follow codegen: integer literals take the type of the value
+// they are used with and are doubles otherwise, comparisons and logical
+// operators give a bool, conditions take one, and loops give 0.0.
+//
+// Unannotated parameters and return types start as type variables, which
+// are unified with the types they are used with, and default to double
+// when nothing constrains them, or to string when they only need a length.
+pub struct TypeChecker {
+    // Signatures of the known functions, by name
+    functions: HashMap<String, (Vec<Type>, Type)>,
+    // Field names of the known structs, by name
+    structs: HashMap<String, Vec<String>>,
+    // Types of the global variables and constants, by name, and whether
+    // each is a constant
+    globals: HashMap<String, (Type, bool)>,
+    // Variables in scope, innermost last
+    variables: Vec<(String, Type)>,
+    // What each type variable has been unified with, if anything
+    substitution: Vec<Option<Type>>,
+    // Types which must turn out to be of a class, with what requires them to
+    classes: Vec<(Type, Class, String)>,
+    // Variables in scope at each lambda and nested definition of the last
+    // item checked, by id, for closure conversion
+    scopes: HashMap<usize, Vec<(String, Type)>>,
+    // Nested functions in scope, by index in variables, with the variables of
+    // the enclosing scopes they use
+    nested: Vec<(usize, Vec<String>)>,
+    // Number of variables in scope captured by the innermost lambda or nested
+    // definition. It gets copies of them, so it cannot assign them.
+    captured: usize,
+    // Variables in scope copied into a closure so far, by index in variables,
+    // which the enclosing scopes cannot assign either
+    frozen: Vec<usize>,
+}
+
+impl TypeChecker {
+    pub fn new() -> TypeChecker {
+        TypeChecker {
+            functions: HashMap::new(),
+            structs: HashMap::new(),
+            globals: HashMap::new(),
+            variables: Vec::new(),
+            substitution: Vec::new(),
+            classes: Vec::new(),
+            scopes: HashMap::new(),
+            nested: Vec::new(),
+            captured: 0,
+            frozen: Vec::new(),
+        }
+    }
+
+    pub fn new_variable(&mut self) -> Type {
+        self.substitution.push(None);
+        Type::Var(self.substitution.len() - 1)
+    }
+
+    // Make a function callable by the items checked afterwards, with the
+    // types left to infer defaulting to double.
+    pub fn declare(&mut self, proto: &mut Prototype) {
+        self.resolve_prototype(proto);
+        self.insert_signature(proto);
+    }
+
+    // Make a struct and its constructor usable by the items checked afterwards.
+    pub fn declare_struct(&mut self, def: &StructDef) {
+        self.structs.insert(def.name.clone(), def.fields.clone());
+        self.functions.insert(def.name.clone(), (vec![Type::Double; def.fields.len()], Type::Struct(def.name.clone())));
+    }
+
+    // Check a global variable or constant, returning its type. Its value is
+    // computed at compile time, so it must be a constant expression.
+    pub fn check_global(&mut self, def: &GlobalDef) -> CheckResult {
+        if self.globals.contains_key(&def.name) {
+            return Err(format!("redefinition of global <{}>", def.name));
+        }
+        if !self.is_constant(&def.value) {
+            return Err(format!("invalid value for <{}>: expected a constant expression", def.name));
+        }
+        self.check_expression(&def.value)
+    }
+
+    // Make a checked global variable or constant visible to the items
+    // checked afterwards.
+    #[inline]
+    pub fn declare_global(&mut self, def: &GlobalDef, ty: Type) {
+        self.globals.insert(def.name.clone(), (ty, def.constant));
+    }
+
+    // Whether an expression can be computed at compile time: literals,
+    // constants, and builtin operators and conversions applied to them.
+    fn is_constant(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::IntegerExpr(_) | Expr::NumberExpr(_) | Expr::BooleanExpr(_) => true,
+            Expr::VariableExpr(v) => match self.globals.get(&v.name) {
+                Some((_, constant)) => *constant,
+                None => false
+            },
+            Expr::UnaryExpr(u) => (u.op == '-' || u.op == '!') && self.is_constant(&u.operand),
+            Expr::BinaryExpr(b) => {
+                // Power calls a function, and logical operators branch.
+                is_builtin_binary(&b.op) && !["=", "&&", "||", "^"].contains(&b.op.as_str())
+                    && self.is_constant(&b.lhs) && self.is_constant(&b.rhs)
+            }
+            Expr::CallExpr(c) => match c.callee_name() {
+                Some("int") | Some("double") | Some("bool") => c.args.len() == 1 && self.is_constant(&c.args[0]),
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    pub fn check_function(&mut self, function: &mut Function) -> Result<(), String> {
+        let proto = &function.proto;
+
+        // Declare the function first so that it can call itself, but drop it
+        // again if its body is invalid, as it will not be generated.
+        let old_signature = self.functions.get(&proto.name).cloned();
+        self.insert_signature(proto);
+
+        self.variables.clear();
+        self.scopes.clear();
+        self.nested.clear();
+        self.frozen.clear();
+        for (arg, ty) in proto.args.iter().zip(proto.arg_types.iter()) {
+            self.variables.push((arg.clone(), ty.clone()));
+        }
+
+        let result = self.check_with_type(&function.body, &proto.return_type).and_then(|ty| {
+            if !self.unify(&ty, &proto.return_type) {
+                Err(format!("invalid return type for <{}>: expected {}, got {}",
+                            proto.name, self.resolve(&proto.return_type), self.resolve(&ty)))
+            } else {
+                Ok(())
+            }
+        }).and_then(|_| self.check_classes());
+        self.classes.clear();
+
+        match result {
+            Ok(()) => self.declare(&mut function.proto),
+            Err(_) => {
+                match old_signature {
+                    Some(signature) => self.functions.insert(proto.name.clone(), signature),
+                    None => self.functions.remove(&proto.name),
+                };
+            }
+        }
+        result
+    }
+
+    pub fn check_expression(&mut self, expr: &Expr) -> CheckResult {
+        self.variables.clear();
+        self.scopes.clear();
+        self.nested.clear();
+        self.frozen.clear();
+        let result = self.check(expr).and_then(|ty| self.check_classes().map(|_| ty));
+        self.classes.clear();
+
+        // The anonymous function the expression is generated into needs its
+        // return type resolved, e.g. for a lambda called right away.
+        result.map(|ty| self.default(&ty))
+    }
+
+    // What a type was inferred to, once the enclosing item is checked.
+    pub fn resolve_type(&mut self, ty: &Type) -> Type {
+        self.default(ty)
+    }
+
+    // The variables a lambda or nested definition of the last item checked
+    // could use from the enclosing scopes.
+    pub fn take_scope(&mut self, id: usize) -> Vec<(String, Type)> {
+        let scope = self.scopes.remove(&id).unwrap_or_default();
+        scope.into_iter().map(|(name, ty)| (name, self.default(&ty))).collect()
+    }
+
+    fn insert_signature(&mut self, proto: &Prototype) {
+        self.functions.insert(proto.name.clone(), (proto.arg_types.clone(), proto.return_type.clone()));
+    }
+
+    // Replace the type variables of a prototype by what they were inferred to.
+    fn resolve_prototype(&mut self, proto: &mut Prototype) {
+        for ty in proto.arg_types.iter_mut() {
+            *ty = self.default(ty);
+        }
+        proto.return_type = self.default(&proto.return_type);
+    }
+
+    // Follow the substitution until a known type or a free type variable,
+    // inside function types as well.
+    fn resolve(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(n) => match self.substitution[*n] {
+                Some(ref ty) => self.resolve(ty),
+                None => ty.clone(),
+            },
+            Type::Function(arg_types, return_type) => {
+                Type::Function(arg_types.iter().map(|ty| self.resolve(ty)).collect(), Box::new(self.resolve(return_type)))
+            }
+            Type::Tuple(types) => Type::Tuple(types.iter().map(|ty| self.resolve(ty)).collect()),
+            _ => ty.clone(),
+        }
+    }
+
+    // Resolve a type, making what is still unconstrained double, or string
+    // if it has to be sized.
+    fn default(&mut self, ty: &Type) -> Type {
+        match self.resolve(ty) {
+            Type::Var(n) => {
+                let sized = self.classes.iter().any(|(ty, class, _)| *class == Class::Sized && self.resolve(ty) == Type::Var(n));
+                let ty = if sized { Type::Str } else { Type::Double };
+                self.substitution[n] = Some(ty.clone());
+                ty
+            }
+            Type::Function(arg_types, return_type) => {
+                let arg_types = arg_types.iter().map(|ty| self.default(ty)).collect();
+                Type::Function(arg_types, Box::new(self.default(&return_type)))
+            }
+            Type::Tuple(types) => Type::Tuple(types.iter().map(|ty| self.default(ty)).collect()),
+            ty => ty,
+        }
+    }
+
+    // Make two types equal, returning whether they can be.
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        match (self.resolve(a), self.resolve(b)) {
+            (Type::Var(m), Type::Var(n)) if m == n => true,
+            (Type::Var(n), ty) | (ty, Type::Var(n)) => {
+                // A function or tuple type cannot contain itself.
+                if self.occurs(n, &ty) {
+                    return false;
+                }
+                self.substitution[n] = Some(ty);
+                true
+            }
+            (Type::Function(a_args, a_return), Type::Function(b_args, b_return)) => {
+                a_args.len() == b_args.len()
+                    && a_args.iter().zip(b_args.iter()).all(|(a, b)| self.unify(a, b))
+                    && self.unify(&a_return, &b_return)
+            }
+            (Type::Tuple(a_types), Type::Tuple(b_types)) => {
+                a_types.len() == b_types.len() && a_types.iter().zip(b_types.iter()).all(|(a, b)| self.unify(a, b))
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    fn occurs(&self, n: usize, ty: &Type) -> bool {
+        match self.resolve(ty) {
+            Type::Var(m) => m == n,
+            Type::Function(arg_types, return_type) => {
+                arg_types.iter().any(|ty| self.occurs(n, ty)) || self.occurs(n, &return_type)
+            }
+            Type::Tuple(types) => types.iter().any(|ty| self.occurs(n, ty)),
+            _ => false,
+        }
+    }
+
+    // Require a type to be a number. Type variables may only be known at the
+    // end, so this is checked by check_classes.
+    fn expect_number(&mut self, ty: &Type, what: String) {
+        self.classes.push((ty.clone(), Class::Number, what));
+    }
+
+    fn expect_scalar(&mut self, ty: &Type, what: String) {
+        self.classes.push((ty.clone(), Class::Scalar, what));
+    }
+
+    fn expect_sized(&mut self, ty: &Type, what: String) {
+        self.classes.push((ty.clone(), Class::Sized, what));
+    }
+
+    // Require a type to be exactly the given one.
+    fn expect(&mut self, ty: &Type, expected: &Type, what: &str) -> Result<(), String> {
+        if !self.unify(ty, expected) {
+            return Err(format!("invalid type for {}: expected {}, got {}", what, expected, self.resolve(ty)));
+        }
+        Ok(())
+    }
+
+    fn check_classes(&mut self) -> Result<(), String> {
+        for (ty, class, what) in self.classes.clone().iter() {
+            let ty = self.default(ty);
+            if !class.contains(&ty) {
+                return Err(format!("invalid type for {}: expected {}, got {}", what, class.name(), ty));
+            }
+        }
+        Ok(())
+    }
+
+    fn expect_bool(&mut self, ty: &Type, what: &str) -> Result<(), String> {
+        self.expect(ty, &Type::Bool, what)
+    }
+
+    fn check(&mut self, expr: &Expr) -> CheckResult {
+        match expr {
+            Expr::IntegerExpr(_) => Ok(Type::Double),
+            Expr::NumberExpr(_) => Ok(Type::Double),
+            Expr::BooleanExpr(_) => Ok(Type::Bool),
+            Expr::StringExpr(_) => Ok(Type::Str),
+            Expr::ArrayExpr(a) => self.check_array(a),
+            Expr::TupleExpr(t) => self.check_tuple(t),
+            Expr::IndexExpr(i) => self.check_index(i),
+            Expr::FieldExpr(f) => self.check_field(f),
+            Expr::VariableExpr(v) => {
+                // A nested function used as a value is a closure of its adapter.
+                if let Some(index) = self.variables.iter().rposition(|(n, _)| *n == v.name) {
+                    self.freeze_nested(index);
+                }
+                self.lookup(&v.name)
+            }
+            Expr::UnaryExpr(u) => self.check_unary(u),
+            Expr::BinaryExpr(b) => self.check_binary(b),
+            Expr::CallExpr(c) => self.check_call(c),
+            Expr::LambdaExpr(l) => self.check_lambda(l),
+            Expr::ClosureExpr(_) => panic!("closure checked after conversion"),
+            Expr::DefExpr(d) => self.check_def(d),
+            Expr::IfExpr(i) => self.check_if(i),
+            Expr::ForExpr(f) => self.check_for(f),
+            Expr::VarExpr(v) => self.check_var(v),
+            Expr::LetExpr(l) => self.check_let(l),
+            Expr::WhileExpr(w) => self.check_while(w),
+            Expr::BreakExpr(_) | Expr::ContinueExpr(_) => Ok(Type::Double),
+            Expr::BlockExpr(b) => self.check_block(b),
+        }
+    }
+
+    // Check an expression where a value of the given type is expected, which
+    // is the type untyped expressions take.
+    fn check_with_type(&mut self, expr: &Expr, ty: &Type) -> CheckResult {
+        if is_untyped(expr) {
+            match expr {
+                Expr::BreakExpr(_) | Expr::ContinueExpr(_) => {}
+                _ => self.expect_number(ty, "integer literal".to_string()),
+            }
+            Ok(ty.clone())
+        } else if let (Expr::TupleExpr(tuple), Type::Tuple(types)) = (expr, self.resolve(ty)) {
+            // The elements of a tuple are checked with those of the expected one.
+            if tuple.elements.len() != types.len() {
+                return self.check_tuple(tuple);
+            }
+            let mut element_types = Vec::new();
+            for (element, ty) in tuple.elements.iter().zip(types.iter()) {
+                element_types.push(self.check_with_type(element, ty)?);
+            }
+            Ok(Type::Tuple(element_types))
+        } else {
+            self.check(expr)
+        }
+    }
+
+    // A variable, or else a function used as a value.
+    fn lookup(&self, name: &str) -> CheckResult {
+        if let Some(index) = self.variables.iter().rposition(|(n, _)| n == name) {
+            // The variables a nested function uses are passed to it where it
+            // is used, so they must still be the ones it was defined with.
+            if let Some((_, used)) = self.nested.iter().find(|(i, _)| *i == index) {
+                if let Some(shadowed) = used.iter().find(|v| self.variables[index + 1..].iter().any(|(n, _)| n == *v)) {
+                    return Err(format!("variable <{}> used by <{}> is shadowed here", shadowed, name));
+                }
+            }
+            return Ok(self.variables[index].1.clone());
+        }
+        if let Some((ty, _)) = self.globals.get(name) {
+            return Ok(ty.clone());
+        }
+        match self.functions.get(name) {
+            Some((arg_types, return_type)) => Ok(Type::Function(arg_types.clone(), Box::new(return_type.clone()))),
+            None => Err(format!("unknown variable name <{}>", name))
+        }
+    }
+
+    // Check a call to a known function.
+    fn check_function_call(&mut self, name: &str, args: &[&Expr]) -> CheckResult {
+        let (arg_types, return_type) = match self.functions.get(name) {
+            Some(signature) => signature.clone(),
+            None => return Err(format!("unknown function name <{}>", name))
+        };
+        self.check_arguments(&format!("<{}>", name), &arg_types, args)?;
+        Ok(return_type)
+    }
+
+    fn check_arguments(&mut self, callee: &str, arg_types: &[Type], args: &[&Expr]) -> Result<(), String> {
+        if arg_types.len() != args.len() {
+            return Err(format!("invalid param number for {}: expected {}, got {}", callee, arg_types.len(), args.len()));
+        }
+
+        for (arg, expected) in args.iter().zip(arg_types.iter()) {
+            let ty = self.check_with_type(arg, expected)?;
+            if !self.unify(&ty, expected) {
+                return Err(format!("invalid argument type for {}: expected {}, got {}",
+                                   callee, self.resolve(expected), self.resolve(&ty)));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_array(&mut self, array: &ArrayExpr) -> CheckResult {
+        for element in array.elements.iter() {
+            let ty = self.check_with_type(element, &Type::Double)?;
+            self.expect(&ty, &Type::Double, "array element")?;
+        }
+        Ok(Type::Array)
+    }
+
+    fn check_tuple(&mut self, tuple: &TupleExpr) -> CheckResult {
+        let mut types = Vec::new();
+        for element in tuple.elements.iter() {
+            types.push(self.check(element)?);
+        }
+        Ok(Type::Tuple(types))
+    }
+
+    fn check_index(&mut self, index: &IndexExpr) -> CheckResult {
+        let array = self.check(&index.array)?;
+        self.expect(&array, &Type::Array, "indexed value")?;
+        let ty = self.check_with_type(&index.index, &Type::Int)?;
+        self.expect(&ty, &Type::Int, "array index")?;
+        Ok(Type::Double)
+    }
+
+    fn check_field(&mut self, field: &FieldExpr) -> CheckResult {
+        let ty = self.check(&field.object)?;
+
+        // A value of unknown type is taken to be of the only struct with
+        // such a field.
+        if let Type::Var(_) = self.resolve(&ty) {
+            let mut candidates = self.structs.iter().filter(|(_, fields)| fields.contains(&field.field));
+            match (candidates.next(), candidates.next()) {
+                (Some((name, _)), None) => {
+                    let name = name.clone();
+                    self.unify(&ty, &Type::Struct(name));
+                }
+                (Some(_), Some(_)) => return Err(format!("ambiguous field <{}>: annotate the type of the struct", field.field)),
+                _ => return Err(format!("unknown field <{}>", field.field)),
+            }
+        }
+
+        match self.resolve(&ty) {
+            Type::Struct(name) => match self.structs.get(&name) {
+                Some(fields) if fields.contains(&field.field) => Ok(Type::Double),
+                Some(_) => Err(format!("unknown field <{}> of struct <{}>", field.field, name)),
+                None => Err(format!("unknown struct <{}>", name)),
+            },
+            ty => Err(format!("invalid type for field access <.{}>: expected a struct, got {}", field.field, ty)),
+        }
+    }
+
+    fn check_unary(&mut self, unary: &UnaryExpr) -> CheckResult {
+        match unary.op {
+            '-' => {
+                let ty = self.check(&unary.operand)?;
+                self.expect_number(&ty, "operand of <->".to_string());
+                Ok(ty)
+            }
+            '!' => {
+                let ty = self.check(&unary.operand)?;
+                self.expect_bool(&ty, "operand of <!>")?;
+                Ok(Type::Bool)
+            }
+            _ => self.check_function_call(&format!("unary{}", unary.op), &[&unary.operand])
+        }
+    }
+
+    fn check_binary(&mut self, binary: &BinaryExpr) -> CheckResult {
+        if binary.op == "=" {
+            let name = match *binary.lhs {
+                Expr::VariableExpr(ref v) => &v.name,
+                Expr::IndexExpr(ref i) => {
+                    self.check_index(i)?;
+                    let ty = self.check_with_type(&binary.rhs, &Type::Double)?;
+                    self.expect(&ty, &Type::Double, "array element")?;
+                    return Ok(ty);
+                }
+                Expr::FieldExpr(ref f) => {
+                    self.check_field(f)?;
+                    let ty = self.check_with_type(&binary.rhs, &Type::Double)?;
+                    self.expect(&ty, &Type::Double, "struct field")?;
+                    return Ok(ty);
+                }
+                _ => return Err("invalid assignment: left side of '=' is not a variable, array element or field".to_string())
+            };
+            let expected = self.lookup(name)?;
+            if let Some(index) = self.variables.iter().rposition(|(n, _)| n == name) {
+                if index < self.captured || self.frozen.contains(&index) {
+                    return Err(format!("invalid assignment: <{}> is captured by a closure and cannot be assigned", name));
+                }
+            }
+            if let Some((_, true)) = self.globals.get(name) {
+                if !self.variables.iter().any(|(n, _)| n == name) {
+                    return Err(format!("invalid assignment: <{}> is a constant and cannot be assigned", name));
+                }
+            }
+            let ty = self.check_with_type(&binary.rhs, &expected)?;
+            if !self.unify(&ty, &expected) {
+                return Err(format!("cannot assign {} to <{}> of type {}", self.resolve(&ty), name, self.resolve(&expected)));
+            }
+            return Ok(ty);
+        }
+
+        if binary.op == "&&" || binary.op == "||" {
+            let what = format!("operand of <{}>", binary.op);
+            let lhs = self.check(&binary.lhs)?;
+            self.expect_bool(&lhs, &what)?;
+            let rhs = self.check(&binary.rhs)?;
+            self.expect_bool(&rhs, &what)?;
+            return Ok(Type::Bool);
+        }
+
+        if !is_builtin_binary(&binary.op) {
+            return self.check_function_call(&format!("binary{}", binary.op), &[&binary.lhs, &binary.rhs]);
+        }
+
+        // An integer literal takes the type of the other operand.
+        let (lhs, rhs) = if is_untyped(&binary.lhs) {
+            let rhs = self.check(&binary.rhs)?;
+            (self.check_with_type(&binary.lhs, &rhs)?, rhs)
+        } else {
+            let lhs = self.check(&binary.lhs)?;
+            let rhs = self.check_with_type(&binary.rhs, &lhs)?;
+            (lhs, rhs)
+        };
+
+        if !self.unify(&lhs, &rhs) {
+            return Err(format!("mismatched operand types for <{}>: {} and {}", binary.op, self.resolve(&lhs), self.resolve(&rhs)));
+        }
+
+        // Bools can only be compared for equality.
+        match binary.op.as_str() {
+            "^" if !self.unify(&lhs, &Type::Double) => Err(format!("invalid binary operation <{}> on {}", binary.op, self.resolve(&lhs))),
+            "==" | "!=" => {
+                self.expect_scalar(&lhs, format!("operand of <{}>", binary.op));
+                Ok(Type::Bool)
+            }
+            "+" | "-" | "*" | "/" | "%" | "^" => {
+                self.expect_number(&lhs, format!("operand of <{}>", binary.op));
+                Ok(lhs)
+            }
+            _ => {
+                self.expect_number(&lhs, format!("operand of <{}>", binary.op));
+                Ok(Type::Bool)
+            }
+        }
+    }
+
+    fn check_call(&mut self, call: &CallExpr) -> CheckResult {
+        let args: Vec<&Expr> = call.args.iter().map(|arg| &**arg).collect();
+
+        // A name which is not a variable calls a builtin or a known function.
+        let name = match call.callee_name() {
+            Some(name) if !self.variables.iter().any(|(n, _)| n == name) && !self.globals.contains_key(name) => Some(name),
+            _ => None,
+        };
+
+        if name == Some("len") {
+            if call.args.len() != 1 {
+                return Err(format!("invalid param number for <len>: expected 1, got {}", call.args.len()));
+            }
+            let ty = self.check(&call.args[0])?;
+            self.expect_sized(&ty, "argument of <len>".to_string());
+            return Ok(Type::Int);
+        }
+
+        if let Some(conversion @ "int") | Some(conversion @ "double") | Some(conversion @ "bool") = name {
+            if call.args.len() != 1 {
+                return Err(format!("invalid param number for <{}>: expected 1, got {}", conversion, call.args.len()));
+            }
+            let ty = self.check(&call.args[0])?;
+            self.expect_scalar(&ty, format!("argument of <{}>", conversion));
+            return Ok(match conversion {
+                "int" => Type::Int,
+                "double" => Type::Double,
+                _ => Type::Bool,
+            });
+        }
+
+        if let Some(name) = name {
+            if self.functions.contains_key(name) {
+                return self.check_function_call(name, &args);
+            }
+        }
+
+        // Anything else must evaluate to a function. One of unknown type is
+        // taken to be of the type it is called with. A nested function called
+        // by name is called directly, not through a closure.
+        let ty = match *call.callee {
+            Expr::VariableExpr(ref v) => self.lookup(&v.name)?,
+            _ => self.check(&call.callee)?,
+        };
+        if let Type::Var(_) = self.resolve(&ty) {
+            let arg_types = args.iter().map(|_| self.new_variable()).collect();
+            let return_type = self.new_variable();
+            self.unify(&ty, &Type::Function(arg_types, Box::new(return_type)));
+        }
+
+        match self.resolve(&ty) {
+            Type::Function(arg_types, return_type) => {
+                self.check_arguments("function call", &arg_types, &args)?;
+                Ok(*return_type)
+            }
+            ty => Err(format!("invalid type for callee: expected a function, got {}", ty)),
+        }
+    }
+
+    fn check_lambda(&mut self, lambda: &LambdaExpr) -> CheckResult {
+        self.scopes.insert(lambda.id, self.variables.clone());
+
+        // The variables it captures, including through the nested functions
+        // it calls, are copied into its environment.
+        for name in closure::free_variables(&lambda.body, &lambda.args) {
+            if let Some(index) = self.variables.iter().rposition(|(n, _)| *n == name) {
+                self.frozen.push(index);
+                self.freeze_nested(index);
+            }
+        }
+
+        let len = self.variables.len();
+        let captured = self.captured;
+        self.captured = len;
+        for (arg, ty) in lambda.args.iter().zip(lambda.arg_types.iter()) {
+            self.variables.push((arg.clone(), ty.clone()));
+        }
+        let result = self.check_with_type(&lambda.body, &lambda.return_type).and_then(|ty| {
+            if !self.unify(&ty, &lambda.return_type) {
+                Err(format!("invalid return type for lambda: expected {}, got {}",
+                            self.resolve(&lambda.return_type), self.resolve(&ty)))
+            } else {
+                Ok(())
+            }
+        });
+        self.truncate_variables(len);
+        self.captured = captured;
+        result?;
+
+        Ok(Type::Function(lambda.arg_types.clone(), Box::new(lambda.return_type.clone())))
+    }
+
+    // The variables a nested function uses are copied into the environment
+    // of its adapter, where it is used as a value or called by a closure.
+    fn freeze_nested(&mut self, index: usize) {
+        let used = match self.nested.iter().find(|(i, _)| *i == index) {
+            Some((_, used)) => used.clone(),
+            None => return,
+        };
+        for name in used {
+            if let Some(index) = self.variables.iter().rposition(|(n, _)| *n == name) {
+                self.frozen.push(index);
+            }
+        }
+    }
+
+    // Leave the scopes of the variables from the given index on.
+    fn truncate_variables(&mut self, len: usize) {
+        self.variables.truncate(len);
+        self.frozen.retain(|index| *index < len);
+    }
+
+    fn check_def(&mut self, def: &DefExpr) -> CheckResult {
+        self.scopes.insert(def.id, self.variables.clone());
+        let proto = &def.function.proto;
+
+        // The variables of the enclosing scopes it uses, including through
+        // the nested functions it calls.
+        let mut bound = vec![proto.name.clone()];
+        bound.extend(proto.args.iter().cloned());
+        let mut used = Vec::new();
+        for name in closure::free_variables(&def.function.body, &bound) {
+            if let Some(index) = self.variables.iter().rposition(|(n, _)| *n == name) {
+                match self.nested.iter().find(|(i, _)| *i == index) {
+                    Some((_, nested_used)) => used.extend(nested_used.iter().cloned()),
+                    None => used.push(name),
+                }
+            }
+        }
+
+        let index = self.variables.len();
+        self.variables.push((proto.name.clone(), Type::Function(proto.arg_types.clone(), Box::new(proto.return_type.clone()))));
+        self.nested.push((index, used));
+
+        let captured = self.captured;
+        self.captured = index + 1;
+        for (arg, ty) in proto.args.iter().zip(proto.arg_types.iter()) {
+            self.variables.push((arg.clone(), ty.clone()));
+        }
+        let result = self.check_with_type(&def.function.body, &proto.return_type).and_then(|ty| {
+            if !self.unify(&ty, &proto.return_type) {
+                Err(format!("invalid return type for <{}>: expected {}, got {}",
+                            proto.name, self.resolve(&proto.return_type), self.resolve(&ty)))
+            } else {
+                Ok(())
+            }
+        });
+        self.truncate_variables(index + 1);
+        self.captured = captured;
+
+        let result = result.and_then(|_| self.check(&def.body));
+        self.truncate_variables(index);
+        self.nested.pop();
+        result
+    }
+
+    fn check_if(&mut self, if_expr: &IfExpr) -> CheckResult {
+        let cond = self.check(&if_expr.cond)?;
+        self.expect_bool(&cond, "condition of <if>")?;
+
+        // An integer literal branch takes the type of the other branch.
+        let (then_type, else_type) = if is_untyped(&if_expr.then_expr) {
+            let else_type = self.check(&if_expr.else_expr)?;
+            (self.check_with_type(&if_expr.then_expr, &else_type)?, else_type)
+        } else {
+            let then_type = self.check(&if_expr.then_expr)?;
+            let else_type = self.check_with_type(&if_expr.else_expr, &then_type)?;
+            (then_type, else_type)
+        };
+
+        if !self.unify(&then_type, &else_type) {
+            return Err(format!("mismatched branch types: {} and {}", self.resolve(&then_type), self.resolve(&else_type)));
+        }
+        Ok(then_type)
+    }
+
+    fn check_for(&mut self, for_expr: &ForExpr) -> CheckResult {
+        let ty = self.check(&for_expr.start)?;
+        self.expect_number(&ty, format!("loop variable <{}>", for_expr.var_name));
+
+        let len = self.variables.len();
+        self.variables.push((for_expr.var_name.clone(), ty.clone()));
+        let result = self.check_loop(for_expr, &ty);
+        self.truncate_variables(len);
+
+        result.map(|_| Type::Double)
+    }
+
+    fn check_loop(&mut self, for_expr: &ForExpr, ty: &Type) -> Result<(), String> {
+        let end = self.check(&for_expr.end)?;
+        self.expect_bool(&end, "condition of <for>")?;
+        if let Some(ref step) = for_expr.step {
+            let step_type = self.check_with_type(step, ty)?;
+            if !self.unify(&step_type, ty) {
+                return Err(format!("invalid step type for <{}>: expected {}, got {}",
+                                   for_expr.var_name, self.resolve(ty), self.resolve(&step_type)));
+            }
+        }
+        self.check(&for_expr.body)?;
+        Ok(())
+    }
+
+    fn check_var(&mut self, var_expr: &VarExpr) -> CheckResult {
+        let len = self.variables.len();
+
+        let mut result = Ok(());
+        for (name, init) in var_expr.vars.iter() {
+            let ty = match init {
+                Some(init) => self.check(init),
+                None => Ok(Type::Double),
+            };
+            match ty {
+                Ok(ty) => self.variables.push((name.clone(), ty)),
+                Err(message) => {
+                    result = Err(message);
+                    break;
+                }
+            }
+        }
+
+        let result = result.and_then(|_| self.check(&var_expr.body));
+        self.truncate_variables(len);
+        result
+    }
+
+    fn check_let(&mut self, let_expr: &LetExpr) -> CheckResult {
+        let ty = self.check(&let_expr.value)?;
+
+        // Destructuring takes a tuple of as many elements as there are names.
+        let types = if let_expr.names.len() == 1 {
+            vec![ty]
+        } else {
+            let types: Vec<Type> = let_expr.names.iter().map(|_| self.new_variable()).collect();
+            if !self.unify(&ty, &Type::Tuple(types.clone())) {
+                return Err(format!("invalid type for value of <let>: expected a tuple of {} elements, got {}",
+                                   let_expr.names.len(), self.resolve(&ty)));
+            }
+            types
+        };
+
+        let len = self.variables.len();
+        self.variables.extend(let_expr.names.iter().cloned().zip(types.into_iter()));
+        let result = self.check(&let_expr.body);
+        self.truncate_variables(len);
+        result
+    }
+
+    fn check_while(&mut self, while_expr: &WhileExpr) -> CheckResult {
+        let cond = self.check(&while_expr.cond)?;
+        self.expect_bool(&cond, "condition of <while>")?;
+        self.check(&while_expr.body)?;
+        Ok(Type::Double)
+    }
+
+    fn check_block(&mut self, block: &BlockExpr) -> CheckResult {
+        let mut ty = Type::Double;
+        for expr in block.exprs.iter() {
+            ty = self.check(expr)?;
+        }
+        Ok(ty)
+    }
+}